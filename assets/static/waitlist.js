@@ -0,0 +1,30 @@
+// Clicking a waiter on the waitlist sends the next number to them specifically.
+// The form is swapped out after every submission, so look everything up on use.
+function setTarget(id) {
+  document.getElementById("target-input").value = id;
+  document.getElementById("target-id").textContent = id;
+  document.getElementById("target-hint").hidden = !id;
+  for (const item of document.querySelectorAll("#waitlist li")) {
+    item.classList.toggle("targeted", item.id === `guid-${id}`);
+  }
+}
+
+document.addEventListener("click", (event) => {
+  const item = event.target.closest("#waitlist li");
+  if (item) {
+    setTarget(item.id.replace(/^guid-/, ""));
+    document.getElementById("random-input").focus();
+  } else if (event.target.closest("#target-clear")) {
+    setTarget("");
+  }
+});
+
+// Forget the target once it has been answered, or if it leaves the waitlist
+function forgetStaleTarget() {
+  const id = document.getElementById("target-input").value;
+  if (!id || !document.getElementById(`guid-${id}`)) {
+    setTarget("");
+  }
+}
+document.addEventListener("htmx:afterSwap", forgetStaleTarget);
+document.addEventListener("htmx:oobAfterSwap", forgetStaleTarget);
@@ -1,44 +1,103 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, Path, State},
+    http::{
+        header::{ACCEPT, LOCATION, RETRY_AFTER},
+        HeaderMap, StatusCode,
+    },
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
+use axum_extra::extract::{Query, SignedCookieJar};
 use redis::AsyncCommands as _;
 use rinja::Template;
+use secrecy::ExposeSecret as _;
 use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
 use uuid::Uuid;
 
 use crate::{
+    bank, banned_list, beacon, entropy,
     error::RrgError,
-    state::{AppState, StateUpdate, BANNED_NUMBERS},
+    fallback::FallbackPolicy,
+    leaderboard::{self, Window},
+    moderation::{self, Action},
+    priority::{self, Priority},
+    provider, quality,
+    receipt::SignedReceipt,
+    request_type::RequestType,
+    sse,
+    state::{AppState, Callback, StateUpdate},
+    stats,
+    submission::{self, Delivery, Submission},
+    waiter::{self, Constraint, Outcome, Waiter},
+    webhook,
 };
 
 #[derive(Deserialize, Debug)]
 struct SubmitParams {
     random_number: String,
+    // Waiter picked from the waitlist, otherwise whoever is next in line
+    #[serde(default, deserialize_with = "empty_as_none")]
+    target: Option<Uuid>,
 }
 
-#[tracing::instrument]
+// Forms send an empty string for inputs that were left blank
+fn empty_as_none<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Uuid>, D::Error> {
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(id) => Uuid::parse_str(id)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// The submission form, with a message about the last submission
+#[derive(Template)]
+#[template(path = "index.html", block = "input_field")]
+pub struct InputFieldTemplate<'a> {
+    pub classes: &'a str,
+    pub context: &'a str,
+}
+
+// Only numbers that were kept go into the beacon, the entropy pool, the
+// statistics and the leaderboards, so ones that were turned away can't skew them
+async fn record_accepted(
+    conn: &mut deadpool_redis::Connection,
+    submission: &Submission,
+) -> anyhow::Result<()> {
+    beacon::record(conn, submission).await?;
+    entropy::mix(conn, submission).await?;
+    quality::record(conn, submission).await?;
+    stats::submitted(conn, &submission.value).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(headers))]
 async fn submit_random(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    jar: SignedCookieJar,
     State(state): State<AppState>,
-    Json(SubmitParams { random_number }): Json<SubmitParams>,
+    Json(SubmitParams {
+        random_number,
+        target,
+    }): Json<SubmitParams>,
 ) -> Result<impl IntoResponse, RrgError> {
-    #[derive(Template)]
-    #[template(path = "index.html", block = "input_field")]
-    struct InputFieldTemplate<'a> {
-        classes: &'a str,
-        context: &'a str,
-    }
-
-    if random_number.len() > 50 || BANNED_NUMBERS.get().unwrap().contains(&random_number) {
-        tracing::warn!("Ignoring banned number");
+    let verdict = moderation::check(&random_number);
+    if random_number.len() > 50 || verdict.action == Action::Reject {
+        tracing::warn!("Ignoring banned number matching {:?}", verdict.rules);
         // Keep track of users who are being mean!
         sentry::configure_scope(|scope| scope.set_tag("naughty_user", "true"));
 
@@ -55,49 +114,186 @@ async fn submit_random(
         ));
     }
 
+    if verdict.action == Action::Shadow {
+        // Don't let on that it went nowhere, so they don't try to get around it
+        tracing::warn!("Dropping banned number matching {:?}", verdict.rules);
+        sentry::configure_scope(|scope| scope.set_tag("naughty_user", "true"));
+
+        return Ok((
+            StatusCode::OK,
+            Html(
+                InputFieldTemplate {
+                    classes: r#"class="success" classes="remove success""#,
+                    context: "Thanks!",
+                }
+                .render()
+                .map_err(anyhow::Error::from)?,
+            ),
+        ));
+    }
+
     sentry::configure_scope(|scope| scope.set_tag("random_number", &random_number));
 
     let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    if verdict.action == Action::Flag {
+        moderation::flag(&mut conn, &random_number, &verdict).await?;
+    }
+
+    let submission = Submission {
+        handle: provider::handle(&mut conn, &jar).await?,
+        ..Submission::new(
+            random_number.clone(),
+            submission::anonymous_handle(
+                &state.handle_salt,
+                addr,
+                &headers,
+                state.behind_fly_proxy,
+            ),
+        )
+    };
+    let conflict = || {
+        Ok::<_, RrgError>((
+            StatusCode::CONFLICT,
+            Html(
+                InputFieldTemplate {
+                    classes: r#"class="warning" classes="remove warning""#,
+                    context: "Someone else got there first!",
+                }
+                .render()
+                .map_err(anyhow::Error::from)?,
+            ),
+        ))
+    };
+
+    if target.is_none() && waiter::waiting(&mut conn).await? == 0 {
+        tracing::debug!("Random number submitted for no active waiters: {random_number}");
+        // Keep it for whoever asks next
+        record_accepted(&mut conn, &submission).await?;
+        bank::deposit(&mut conn, &submission).await?;
+
+        return Ok((
+            StatusCode::OK,
+            Html(
+                InputFieldTemplate {
+                    classes: r#"class="success" classes="remove success""#,
+                    context: "Nobody's waiting right now, so your number was banked!",
+                }
+                .render()
+                .map_err(anyhow::Error::from)?,
+            ),
+        ));
+    }
+
+    let waiters = match target {
+        // A provider picked someone from the waitlist, nobody else will do
+        Some(target) => match waiter::get(&mut conn, target).await? {
+            Some(waiter) => vec![waiter],
+            None => return conflict(),
+        },
+        None => waiter::accepting(&mut conn, &random_number).await?,
+    };
+
+    // Hand the number to the waiter that is next in line, by priority and then by
+    // how long it has been waiting, out of those willing to accept it
+    let candidates = priority::order(
+        &mut conn,
+        waiters
+            .iter()
+            .filter(|waiter| waiter.constraint.accepts(&random_number)),
+    )
+    .await?;
+    if candidates.is_empty() {
+        tracing::debug!("Random number submitted that no waiter can use: {random_number}");
+
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Html(
+                InputFieldTemplate {
+                    classes: r#"class="error" classes="remove error""#,
+                    context: if target.is_some() {
+                        "They can't use that number!"
+                    } else {
+                        "Nobody waiting can use that number!"
+                    },
+                }
+                .render()
+                .map_err(anyhow::Error::from)?,
+            ),
+        ));
+    }
+
+    let mut recipient = None;
+    for candidate in candidates {
+        if let Some(received) =
+            waiter::claim(&mut conn, candidate, &submission, state.ticket_retention).await?
+        {
+            recipient = Some((candidate, received));
+            break;
+        }
+    }
 
     // If there is someone waiting for a random number...
-    if let Some(guid) = conn
-        .rpop("pending_callbacks", None)
-        .await
-        .map_err(anyhow::Error::from)?
-    {
-        tracing::debug!("Random number submitted: {random_number}, returning to client: {guid}");
+    if let Some((waiter, received)) = recipient {
+        let guid = waiter.id;
+        record_accepted(&mut conn, &submission).await?;
+        priority::served(&mut conn, waiter.priority).await?;
+        if let Some(handle) = &submission.handle {
+            provider::delivered(
+                &mut conn,
+                handle,
+                submission.submitted_at.saturating_sub(waiter.created_at),
+            )
+            .await?;
+        }
+        tracing::debug!(
+            "Random number submitted: {random_number}, returning to client: {guid} ({received}/{})",
+            waiter.needed()
+        );
         sentry::configure_scope(|scope| {
             scope.set_tag("associated_guid", guid);
         });
 
-        // Send the random number over the waiter's response channel
+        // Notify the waiter that it has a new random number
         conn.publish::<_, _, ()>(
             "callbacks",
-            serde_json::to_string(&(guid, &random_number)).unwrap(),
+            serde_json::to_string(&(guid, Callback::Received(received))).unwrap(),
         )
         .await
         .map_err(anyhow::Error::from)?;
 
-        // Indicate to any open provider portals that the user no longer needs a number
+        let state_update = if received == waiter.needed() {
+            stats::fulfilled(&mut conn, waiter).await?;
+            webhook::enqueue(&mut conn, waiter, &state.signer).await?;
+
+            // Indicate to any open provider portals that the user no longer needs a number
+            StateUpdate::Removed(guid)
+        } else {
+            // Or show them how far along it is
+            StateUpdate::Updated(Waiter {
+                received,
+                ..waiter.clone()
+            })
+        };
         conn.publish::<_, _, ()>(
             "state_updates",
-            serde_json::to_string(&StateUpdate::Removed(guid)).unwrap(),
+            serde_json::to_string(&state_update).unwrap(),
         )
         .await
         .map_err(anyhow::Error::from)?;
-
-        conn.zincr::<_, _, _, ()>("counts", &random_number, 1)
-            .await
-            .map_err(anyhow::Error::from)?;
+    } else if target.is_some() {
+        tracing::debug!("Targeted waiter was served before {random_number} arrived");
+        return conflict();
     } else {
-        tracing::debug!("Random number submitted for no active waiters: {random_number}");
+        tracing::debug!("Every waiter that could use {random_number} was already served");
+        record_accepted(&mut conn, &submission).await?;
+        bank::deposit(&mut conn, &submission).await?;
 
         return Ok((
             StatusCode::OK,
             Html(
                 InputFieldTemplate {
-                    classes: r#"class="warning" classes="remove warning""#,
-                    context: "Nobody got your number!",
+                    classes: r#"class="success" classes="remove success""#,
+                    context: "Someone beat you to it, so your number was banked!",
                 }
                 .render()
                 .map_err(anyhow::Error::from)?,
@@ -118,22 +314,308 @@ async fn submit_random(
     ));
 }
 
-#[tracing::instrument]
+// Set on responses whose numbers were (partly) made up by a fallback policy
+const FALLBACK_POLICY_HEADER: &str = "x-fallback-policy";
+
+// Most values a single request can ask for
+const MAX_COUNT: usize = 100;
+
+// In characters
+const MAX_NOTE_LENGTH: usize = 100;
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+#[derive(Deserialize, Debug)]
+struct GetParams {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    precision: Option<u32>,
+    bytes: Option<usize>,
+    count: Option<usize>,
+    min: Option<i64>,
+    max: Option<i64>,
+    #[serde(default)]
+    not: Vec<i64>,
+    // Only for tickets, where to send the numbers once they arrive
+    callback_url: Option<String>,
+    // What to do if nobody submits in time, overriding the server default
+    fallback: Option<FallbackPolicy>,
+    // Defaults to the highest priority the API key allows
+    priority: Option<Priority>,
+    // Seconds to wait before giving up, takes precedence over `Prefer: wait=`
+    timeout: Option<u64>,
+    // Shown to providers on the waitlist
+    note: Option<String>,
+    nickname: Option<String>,
+    // Secret chosen by the client to pick the same wait back up after a dropped connection
+    resume: Option<String>,
+}
+
+impl GetParams {
+    fn into_waiter(self, guid: Uuid, priority: Priority) -> Result<Waiter, RrgError> {
+        let request_type =
+            RequestType::from_params(self.kind.as_deref(), self.precision, self.bytes)?;
+        let constraint =
+            request_type.draw_constraint(Constraint::new(self.min, self.max, self.not)?)?;
+        let count = self.count.unwrap_or(1);
+        if !(1..=MAX_COUNT).contains(&count) {
+            return Err(RrgError::BadRequest(format!(
+                "count must be between 1 and {MAX_COUNT}"
+            )));
+        }
+
+        Ok(Waiter {
+            id: guid,
+            note: moderate("note", self.note, MAX_NOTE_LENGTH)?,
+            nickname: moderate("nickname", self.nickname, MAX_NICKNAME_LENGTH)?,
+            request_type,
+            constraint,
+            count,
+            created_at: submission::now_millis(),
+            ticket: false,
+            priority,
+            received: 0,
+        })
+    }
+}
+
+// Text for the waitlist goes through the same filter as submitted numbers
+fn moderate(
+    field: &str,
+    text: Option<String>,
+    max_length: usize,
+) -> Result<Option<String>, RrgError> {
+    let Some(text) = text.map(|text| text.trim().to_string()) else {
+        return Ok(None);
+    };
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text.chars().count() > max_length {
+        return Err(RrgError::BadRequest(format!(
+            "{field} must be at most {max_length} characters"
+        )));
+    }
+    // Shadow dropping a note would just be dropping it
+    let verdict = moderation::check(&text);
+    if verdict.action >= Action::Shadow {
+        tracing::warn!("Rejecting banned {field} matching {:?}", verdict.rules);
+        sentry::configure_scope(|scope| scope.set_tag("naughty_user", "true"));
+        return Err(RrgError::BadRequest(format!("{field} is not allowed")));
+    }
+    if verdict.action == Action::Flag {
+        tracing::warn!("Flagged {field} matching {:?}", verdict.rules);
+        sentry::configure_scope(|scope| scope.set_tag("flagged", "true"));
+    }
+    Ok(Some(text))
+}
+
+// Anonymous clients get normal priority, API keys may allow a higher one.
+// Anyone can ask for a lower priority than they are allowed.
+fn request_priority(
+    headers: &HeaderMap,
+    state: &AppState,
+    requested: Option<Priority>,
+) -> Result<Priority, RrgError> {
+    let allowed = match headers.get(priority::API_KEY_HEADER) {
+        Some(key) => state
+            .api_keys
+            .get(key.to_str()?)
+            .ok_or_else(|| RrgError::Forbidden("Unknown API key".to_string()))?,
+        None => Priority::default(),
+    };
+    match requested {
+        Some(priority) if priority > allowed => Err(RrgError::Forbidden(format!(
+            "Priority {priority} needs an API key that allows it"
+        ))),
+        Some(priority) => Ok(priority),
+        None => Ok(allowed),
+    }
+}
+
+// The `wait` preference from any `Prefer` headers, in seconds
+fn wait_preference(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|prefer| prefer.to_str().ok())
+        .flat_map(|prefer| prefer.split([',', ';']))
+        .find_map(|preference| {
+            let (name, value) = preference.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("wait")
+                .then(|| value.trim().trim_matches('"').parse().ok())
+                .flatten()
+        })
+}
+
+// Plain text responses carry the receipt in headers, the numbers are the body
+// and the fallback is in `x-fallback-policy`
+fn receipt_headers(signed: &SignedReceipt) -> [(&'static str, String); 4] {
+    [
+        (
+            "x-rrg-receipt-request-id",
+            signed.receipt.request_id.to_string(),
+        ),
+        (
+            "x-rrg-receipt-fulfilled-at-ms",
+            signed.receipt.fulfilled_at_ms.to_string(),
+        ),
+        (
+            "x-rrg-receipt-providers-hash",
+            signed.receipt.providers_hash.clone(),
+        ),
+        ("x-rrg-receipt-signature", signed.signature.clone()),
+    ]
+}
+
+// Lets the requester cancel a request later. Clients choose their own for plain
+// requests, and are given one for tickets and event streams if they don't.
+pub const REQUEST_SECRET_HEADER: &str = "x-request-secret";
+
+fn new_secret() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+// Grab the request-id from request headers.
+// This is a header that is inserted by the server for request tracking,
+// so we can be sure that it exists and is a valid UUID.
+fn request_id(headers: &HeaderMap) -> Result<Uuid, RrgError> {
+    let request_id_header = headers["x-request-id"].to_str()?;
+    Ok(Uuid::parse_str(request_id_header)
+        .inspect_err(|_| tracing::warn!("Invalid x-request-id '{request_id_header}'"))?)
+}
+
+#[tracing::instrument(skip(headers))]
 async fn get_random(
     headers: HeaderMap,
+    Query(params): Query<GetParams>,
     State(state): State<AppState>,
 ) -> Result<Response, RrgError> {
-    // Grab the request-id from request headers.
-    // This is a header that is inserted by the server for request tracking,
-    // so we can be sure that it exists and is a valid UUID.
-    let request_id_header = headers["x-request-id"].to_str()?;
-    let guid = Uuid::parse_str(request_id_header)
-        .inspect_err(|_| tracing::warn!("Invalid x-request-id '{request_id_header}'"))?;
+    if params.callback_url.is_some() {
+        return Err(RrgError::BadRequest(
+            "callback_url is only supported by POST /api/requests".to_string(),
+        ));
+    }
+    // Event streams can always be resumed. Their event ids are the resume token
+    // rather than the waiter id, which anyone can read off the waitlist, so a
+    // reconnecting client sends it back as `Last-Event-ID`.
+    let event_stream = accepts(&headers, "text/event-stream");
+    let last_event_id = headers
+        .get("last-event-id")
+        .filter(|_| event_stream)
+        .map(|id| id.to_str().map(str::to_string))
+        .transpose()?;
+    // The stream already ended and the client heard how, which it can only stop
+    // reconnecting after if told there's no content
+    if last_event_id.as_deref() == Some(sse::FINISHED_EVENT_ID) {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    let reconnecting = last_event_id.is_some();
+    let resume = if event_stream {
+        Some(
+            last_event_id
+                .or_else(|| params.resume.clone())
+                .unwrap_or_else(new_secret),
+        )
+    } else {
+        params.resume.clone()
+    };
+    let resumable = resume.is_some();
+    let guid = match &resume {
+        Some(token) => waiter::resume_id(token)?,
+        None => request_id(&headers)?,
+    };
+    let policy = params.fallback.unwrap_or(state.fallback_policy);
+    let priority = request_priority(&headers, &state, params.priority)?;
+
+    // Clients may choose how long to wait, up to the server maximum
+    let preferred_wait = wait_preference(&headers);
+    let deadline = params
+        .timeout
+        .or(preferred_wait)
+        .map_or(state.request_timeout, |seconds| {
+            Duration::from_secs(seconds).min(state.max_wait)
+        });
+    let preference_applied = (params.timeout.is_none() && preferred_wait.is_some())
+        .then(|| [("preference-applied", format!("wait={}", deadline.as_secs()))]);
+    let deadline = waiter::Deadline {
+        wait: deadline,
+        policy,
+    };
+
+    let waiter = params.into_waiter(guid, priority)?;
+    let request_secret = headers
+        .get(REQUEST_SECRET_HEADER)
+        .map(|secret| secret.to_str())
+        .transpose()?;
 
     let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
 
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    state.callback_map.lock().unwrap().insert(guid, tx);
+    if let Some(token) = resume.filter(|_| event_stream) {
+        // A reconnecting client picks its existing wait back up rather than queueing
+        // again
+        let (waiter, generated) = match waiter::get(&mut conn, guid).await? {
+            Some(waiter) => (waiter, None),
+            // It finished while the client was away, or there is nothing to pick
+            // back up, in which case queueing again would have a client that never
+            // closes its stream take numbers forever
+            None if reconnecting => {
+                let replayed = sse::replay(&mut conn, guid).await?;
+                return Ok(replayed.unwrap_or_else(|| StatusCode::NO_CONTENT.into_response()));
+            }
+            None => {
+                let banked = withdraw_banked(&mut conn, &state, &waiter).await?;
+                if !banked.is_empty() {
+                    return Ok(sse::delivered(&mut conn, &state, &waiter, &banked).await?);
+                }
+                // Clients that didn't bring their own secret are sent one
+                let secret = request_secret.map_or_else(new_secret, str::to_string);
+                waiter::register(&mut conn, &waiter, Some(&secret), None).await?;
+                (waiter, request_secret.is_none().then_some(secret))
+            }
+        };
+        return Ok((
+            preference_applied,
+            sse::wait(state, waiter, token, generated, deadline),
+        )
+            .into_response());
+    }
+
+    // A client that lost its connection keeps its place in the queue, along with
+    // anything that arrived while it was away
+    let resumed = if resumable {
+        waiter::get(&mut conn, guid).await?
+    } else {
+        None
+    };
+    let is_new = resumed.is_none();
+    let waiter = match resumed {
+        Some(waiter) => {
+            tracing::debug!("{guid} resumed waiting");
+            waiter
+        }
+        None => {
+            // No need to wait if someone already left enough numbers in the bank
+            let banked = withdraw_banked(&mut conn, &state, &waiter).await?;
+            if !banked.is_empty() {
+                let delivery = Delivery::new(&waiter, &banked, None, &state.signer);
+                return Ok(delivery_response(&headers, delivery));
+            }
+            waiter
+        }
+    };
+
+    if is_new {
+        // Register as a new waiter for a random number, before listening for it
+        // so that a clash doesn't disturb whoever holds the id
+        waiter::register(&mut conn, &waiter, request_secret, None).await?;
+    }
+
+    let (listener, mut rx) = waiter::listen(&state, guid);
+    if resumable {
+        // Any connection that had it before, here or elsewhere, stands down
+        waiter::attach(&mut conn, &listener, state.resume_grace).await?;
+    }
 
     // If the request is cancelled or times out, this task will be cancelled
     // but we still need to remove the new guid from the pending_callbacks list.
@@ -145,59 +627,460 @@ async fn get_random(
     let removed_clone = removed.clone();
 
     // Span a task to remove the guid from the pending_callbacks list
+    let drop_state = state.clone();
+    let drop_listener = listener.clone();
     tokio::spawn(async move {
+        let state = drop_state;
         // Wait for the token to be cancelled by drop
         token.cancelled().await;
-        // If the guid was already removed from pending callbacks, do nothing.
-        if removed_clone.load(Ordering::Acquire) {
+        // If the guid was already removed from pending callbacks, or another
+        // connection resumed it, do nothing.
+        if !drop_listener.stop() || removed_clone.load(Ordering::Acquire) {
             return;
         }
 
-        // Otherwise, remove the guid
-        let mut conn = state.redis.get().await.unwrap();
-        conn.lrem::<_, _, ()>("pending_callbacks", 1, guid)
-            .await
-            .unwrap();
-        conn.publish::<_, _, ()>(
-            "state_updates",
-            serde_json::to_string(&StateUpdate::Removed(guid)).unwrap(),
-        )
-        .await
-        .unwrap();
+        // Otherwise, hold its place for a while if it can resume, or remove it
+        if let Err(e) = waiter::disconnected(&state, &drop_listener, resumable).await {
+            tracing::error!("Unable to clean up after {guid}: {e:?}");
+        }
     });
 
-    // Register as a new waiter for a random number
-    conn.lpush::<_, _, ()>("pending_callbacks", guid)
-        .await
-        .map_err(anyhow::Error::from)?;
+    // Wait for enough random numbers to be sent by providers, letting other
+    // instances know the client is still here if it can resume. Anything that
+    // arrived before we were listening is already stored.
+    let needed = waiter.needed();
+    let mut received = waiter::values(&mut conn, guid).await?.len();
+    let mut cancelled = false;
+    // Counted from when the wait began, so that resuming doesn't extend it
+    let waited = tokio::time::timeout_at(deadline.expires_at(&waiter), async {
+        let mut attach_interval = tokio::time::interval(waiter::hold_interval(state.resume_grace));
+        while received < needed {
+            tokio::select! {
+                notification = rx.recv() => match notification {
+                    Some(Callback::Received(notification)) => {
+                        received = received.max(notification);
+                    }
+                    Some(Callback::Cancelled) => {
+                        cancelled = true;
+                        break;
+                    }
+                    Some(Callback::Superseded) | None => break,
+                },
+                _ = attach_interval.tick(), if resumable => {
+                    if !waiter::hold(&mut conn, &listener, state.resume_grace).await? {
+                        // Resumed by a connection to another instance
+                        break;
+                    }
+                }
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await;
+    let timed_out = match waited {
+        Ok(waited) => {
+            waited?;
+            false
+        }
+        Err(_) => true,
+    };
+
+    if !listener.stop() {
+        // The connection that resumed it takes care of it
+        removed.store(true, Ordering::Release);
+        drop(drop_guard);
+        return Err(RrgError::Conflict(
+            "This request was resumed by another connection".to_string(),
+        ));
+    }
+
+    if cancelled {
+        // Whoever cancelled it already cleaned up
+        removed.store(true, Ordering::Release);
+        drop(drop_guard);
+
+        let body = if accepts(&headers, "application/json") {
+            Json(serde_json::json!({ "id": guid, "status": "cancelled" })).into_response()
+        } else {
+            "cancelled\n".into_response()
+        };
+        return Ok((StatusCode::GONE, body).into_response());
+    }
+
+    // A connection to another instance may have resumed it too
+    let outcome = if waiter::hold(&mut conn, &listener, state.resume_grace).await? {
+        waiter::conclude(&mut conn, &state, &waiter, timed_out, deadline).await?
+    } else {
+        Outcome::Gone
+    };
+
+    // Mark the guid as removed...
+    removed.store(true, Ordering::Release);
+    // and manually drop the drop_guard to trigger the cancellation token
+    drop(drop_guard);
+
+    match outcome {
+        Outcome::Delivered(delivery) => {
+            Ok((preference_applied, delivery_response(&headers, delivery)).into_response())
+        }
+        Outcome::Expired(expired) => Ok((
+            StatusCode::GATEWAY_TIMEOUT,
+            preference_applied,
+            [
+                (
+                    RETRY_AFTER.as_str(),
+                    state.request_timeout.as_secs().to_string(),
+                ),
+                (FALLBACK_POLICY_HEADER, policy.to_string()),
+            ],
+            Json(expired),
+        )
+            .into_response()),
+        Outcome::Gone => Err(RrgError::Conflict(
+            "This request was finished by another connection or cancelled".to_string(),
+        )),
+    }
+}
+
+async fn withdraw_banked(
+    conn: &mut deadpool_redis::Connection,
+    state: &AppState,
+    waiter: &Waiter,
+) -> anyhow::Result<Vec<Submission>> {
+    let banked =
+        bank::withdraw(conn, &waiter.constraint, waiter.needed(), state.bank_expiry).await?;
+    if !banked.is_empty() {
+        tracing::debug!("Serving {} from the bank", waiter.id);
+        stats::fulfilled(conn, waiter).await?;
+    }
+    Ok(banked)
+}
+
+fn accepts(headers: &HeaderMap, content_type: &str) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(content_type))
+}
+
+// Plain text by default so the output is nice for curl users. Numbers that
+// didn't all come from people are labelled with the fallback that made them up.
+// The full receipt is only in JSON responses, plain text just gets its
+// signature.
+fn delivery_response(headers: &HeaderMap, delivery: Delivery) -> Response {
+    let fallback_header = delivery
+        .fallback
+        .map(|policy| [(FALLBACK_POLICY_HEADER, policy.to_string())]);
+
+    if !accepts(headers, "application/json") {
+        let random_numbers = &delivery.receipt.receipt.numbers;
+        tracing::debug!("Returning random numbers to client: {random_numbers:?}");
+        return (
+            StatusCode::OK,
+            fallback_header,
+            receipt_headers(&delivery.receipt),
+            format!("{}\n", random_numbers.join("\n")),
+        )
+            .into_response();
+    }
+
+    tracing::debug!("Returning delivery to client: {delivery:?}");
+    (fallback_header, Json(delivery)).into_response()
+}
+
+/// Register a waiter without holding the connection open. The client polls
+/// the returned location until the numbers arrive.
+#[tracing::instrument(skip(headers))]
+async fn create_ticket(
+    headers: HeaderMap,
+    Query(params): Query<GetParams>,
+    State(state): State<AppState>,
+) -> Result<Response, RrgError> {
+    // Tickets are kept for the ticket timeout and polled by id instead
+    let unsupported = [
+        ("fallback", params.fallback.is_some()),
+        ("timeout", params.timeout.is_some()),
+        ("resume", params.resume.is_some()),
+    ];
+    if let Some((param, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(RrgError::BadRequest(format!(
+            "{param} is only supported by GET /api/get"
+        )));
+    }
+    // Chosen here rather than taken from the request, so nobody can pick the id of
+    // someone else's ticket
+    let guid = Uuid::now_v7();
+    let callback_url = params.callback_url.clone();
+    let priority = request_priority(&headers, &state, params.priority)?;
+    let waiter = Waiter {
+        ticket: true,
+        ..params.into_waiter(guid, priority)?
+    };
+
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+
+    // Only the requester knows the secret, which cancels the ticket and signs
+    // webhook payloads
+    let secret = new_secret();
+    if let Some(url) = callback_url {
+        webhook::validate_url(&url).await?;
+        webhook::register(&mut conn, guid, url, secret.clone(), state.ticket_timeout).await?;
+    }
+
+    waiter::register(
+        &mut conn,
+        &waiter,
+        Some(&secret),
+        Some(state.ticket_timeout),
+    )
+    .await?;
+
+    let location = format!("/api/requests/{guid}");
+    let body = if accepts(&headers, "application/json") {
+        Json(serde_json::json!({ "id": guid, "location": location, "secret": secret }))
+            .into_response()
+    } else {
+        format!("{guid}\n{secret}\n").into_response()
+    };
+    Ok((StatusCode::CREATED, [(LOCATION, location)], body).into_response())
+}
+
+/// Stop waiting for a request that hasn't been fulfilled yet. Anyone still
+/// waiting on it is told that it was cancelled.
+#[tracing::instrument(skip(headers))]
+async fn cancel_request(
+    headers: HeaderMap,
+    Path(guid): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Response, RrgError> {
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+
+    if waiter::get(&mut conn, guid).await?.is_none() {
+        return Err(RrgError::NotFound);
+    }
+
+    waiter::check_secret(&mut conn, guid, required_secret(&headers)?).await?;
+
+    // Once it is out of the queue nobody can claim it. If it was already out then
+    // it has everything it asked for.
+    if !waiter::withdraw(&mut conn, guid).await? {
+        return Err(RrgError::Conflict(
+            "This request has already been fulfilled".to_string(),
+        ));
+    }
+    tracing::debug!("{guid} cancelled by the requester");
+    waiter::abandon(&mut conn, guid).await?;
+
+    // Wake up whichever instance is holding a connection for it
     conn.publish::<_, _, ()>(
-        "state_updates",
-        serde_json::to_string(&StateUpdate::Added(guid)).unwrap(),
+        "callbacks",
+        serde_json::to_string(&(guid, Callback::Cancelled)).unwrap(),
     )
     .await
     .map_err(anyhow::Error::from)?;
 
-    // Wait for the random number to be sent by a provider
-    let callback_result = rx.await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
 
-    conn.lrem::<_, _, ()>("pending_callbacks", 1, guid)
+#[derive(Deserialize, Debug)]
+struct BytesParams {
+    n: Option<usize>,
+}
+
+// As much as the entropy pool can ever hold
+const MAX_BYTES: usize = 64;
+
+const ENTROPY_ESTIMATE_HEADER: &str = "x-entropy-estimate-bits";
+
+/// Uniform random bytes extracted from submissions, hex encoded. Only
+/// available once enough entropy has been gathered for them.
+#[tracing::instrument(skip(headers))]
+async fn get_bytes(
+    headers: HeaderMap,
+    Query(BytesParams { n }): Query<BytesParams>,
+    State(state): State<AppState>,
+) -> Result<Response, RrgError> {
+    let n = n.unwrap_or(32);
+    if !(1..=MAX_BYTES).contains(&n) {
+        return Err(RrgError::BadRequest(format!(
+            "n must be between 1 and {MAX_BYTES}"
+        )));
+    }
+
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    let bytes = entropy::draw(&mut conn, n).await?;
+    let estimate = entropy::estimate(&mut conn).await?;
+    let estimate_header = [(ENTROPY_ESTIMATE_HEADER, format!("{estimate:.1}"))];
+
+    let Some(bytes) = bytes else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            estimate_header,
+            [(RETRY_AFTER, "60")],
+            Json(serde_json::json!({
+                "error": "Not enough entropy has been gathered yet",
+                "entropy_bits": estimate,
+                "needed_bits": n * 8,
+            })),
+        )
+            .into_response());
+    };
+
+    let body = if accepts(&headers, "application/json") {
+        Json(serde_json::json!({ "bytes": hex::encode(&bytes), "entropy_bits": estimate }))
+            .into_response()
+    } else {
+        format!("{}\n", hex::encode(&bytes)).into_response()
+    };
+    Ok((estimate_header, body).into_response())
+}
+
+/// Randomness tests over every submission so far
+#[tracing::instrument]
+async fn quality_report(State(state): State<AppState>) -> Result<impl IntoResponse, RrgError> {
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    Ok(Json(quality::report(&mut conn).await?))
+}
+
+#[derive(Deserialize, Debug)]
+struct StatsParams {
+    window: Option<Window>,
+    // Starting from 1
+    page: Option<usize>,
+    size: Option<usize>,
+}
+
+const MAX_STATS_PAGE_SIZE: usize = 100;
+
+/// Leaderboards and summary figures for dashboards
+#[tracing::instrument]
+async fn get_stats(
+    Query(StatsParams { window, page, size }): Query<StatsParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RrgError> {
+    let window = window.unwrap_or_default();
+    let page = page.unwrap_or(1);
+    // The site's leaderboard may be longer than anyone can ask for here
+    let size = size.unwrap_or(state.leaderboard_size.min(MAX_STATS_PAGE_SIZE));
+    if !(1..=MAX_STATS_PAGE_SIZE).contains(&size) {
+        return Err(RrgError::BadRequest(format!(
+            "size must be between 1 and {MAX_STATS_PAGE_SIZE}"
+        )));
+    }
+    let offset =
+        leaderboard::offset(page, size).map_err(|e| RrgError::BadRequest(e.to_string()))?;
+
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    let leaderboard = leaderboard::top(&mut conn, window, offset, size).await?;
+    let summary = stats::summary(&mut conn).await?;
+
+    let entries = leaderboard
+        .entries
+        .into_iter()
+        .enumerate()
+        .map(|(i, (number, count))| {
+            serde_json::json!({ "rank": offset + i + 1, "number": number, "count": count })
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(serde_json::json!({
+        "window": window,
+        "page": page,
+        "size": size,
+        "total_entries": leaderboard.total,
+        "entries": entries,
+        "summary": summary,
+        // On this instance
+        "banned_list": state.banned_list.stats(),
+    })))
+}
+
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+// Admin routes don't exist unless a token is configured
+fn require_admin(headers: &HeaderMap, state: &AppState) -> Result<(), RrgError> {
+    let token = state.admin_token.as_ref().ok_or(RrgError::NotFound)?;
+    let given = headers
+        .get(ADMIN_TOKEN_HEADER)
+        .map(|given| given.to_str())
+        .transpose()?;
+    // Compare digests so how long it takes says nothing about the token
+    if given.map(Sha256::digest) == Some(Sha256::digest(token.expose_secret())) {
+        Ok(())
+    } else {
+        Err(RrgError::Forbidden("Admin token required".to_string()))
+    }
+}
+
+/// Ask every instance to reload the banned list
+#[tracing::instrument(skip(headers))]
+async fn reload_banned_list(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RrgError> {
+    require_admin(&headers, &state)?;
+    tracing::info!("Banned list reload requested");
+
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    conn.publish::<_, _, ()>(banned_list::RELOAD_CHANNEL, "")
         .await
         .map_err(anyhow::Error::from)?;
-    conn.publish::<_, _, ()>(
-        "state_updates",
-        serde_json::to_string(&StateUpdate::Removed(guid)).unwrap(),
-    )
-    .await
-    .map_err(anyhow::Error::from)?;
+    // How it went shows up in /api/stats
+    Ok(StatusCode::ACCEPTED)
+}
 
-    // Mark the guid as removed...
-    removed.store(true, Ordering::Release);
-    // and manually drop the drop_guard to trigger the cancellation token
-    drop(drop_guard);
+/// Check that a receipt was signed by this server
+#[tracing::instrument]
+async fn verify_receipt(
+    State(state): State<AppState>,
+    Json(signed): Json<SignedReceipt>,
+) -> impl IntoResponse {
+    Json(serde_json::json!({ "valid": state.signer.verify(&signed) }))
+}
+
+fn required_secret(headers: &HeaderMap) -> Result<&str, RrgError> {
+    let Some(secret) = headers.get(REQUEST_SECRET_HEADER) else {
+        return Err(RrgError::Forbidden(format!(
+            "{REQUEST_SECRET_HEADER} is required"
+        )));
+    };
+    Ok(secret.to_str()?)
+}
+
+/// Collect a ticket, or see how far along it is. Ticket ids are on the public
+/// waitlist, so only the requester's secret is accepted.
+#[tracing::instrument(skip(headers))]
+async fn get_ticket(
+    headers: HeaderMap,
+    Path(guid): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Response, RrgError> {
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
 
-    let random_number = callback_result.expect("Sender unexpectedly dropped");
-    tracing::debug!("Returning random number to client: {random_number:?}");
-    Ok((StatusCode::OK, format!("{random_number}\n",)).into_response())
+    // Expired, already past its retention period, or never existed
+    let Some(waiter) = waiter::get(&mut conn, guid).await? else {
+        return Err(RrgError::NotFound);
+    };
+    waiter::check_secret(&mut conn, guid, required_secret(&headers)?).await?;
+
+    let submissions = waiter::values(&mut conn, guid).await?;
+    if submissions.len() < waiter.needed() {
+        let (received, needed) = (submissions.len(), waiter.needed());
+        let body = if accepts(&headers, "application/json") {
+            Json(serde_json::json!({
+                "id": guid,
+                "status": "pending",
+                "received": received,
+                "needed": needed,
+            }))
+            .into_response()
+        } else {
+            format!("pending ({received}/{needed})\n").into_response()
+        };
+        return Ok((StatusCode::ACCEPTED, body).into_response());
+    }
+
+    let delivery = Delivery::new(&waiter, &submissions, None, &state.signer);
+    Ok(delivery_response(&headers, delivery))
 }
 
 async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
@@ -213,5 +1096,17 @@ pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/get", get(get_random))
         .route("/submit", post(submit_random))
+        .route("/requests", post(create_ticket))
+        .route("/requests/{id}", get(get_ticket).delete(cancel_request))
+        .route("/verify", post(verify_receipt))
+        .route(
+            "/handle",
+            post(provider::set_handle).delete(provider::clear_handle),
+        )
+        .route("/bytes", get(get_bytes))
+        .nest("/beacon", beacon::routes())
+        .route("/stats", get(get_stats))
+        .route("/stats/quality", get(quality_report))
+        .route("/admin/banned-list/reload", post(reload_banned_list))
         .route("/health", get(health_check))
 }
@@ -0,0 +1,106 @@
+use std::{sync::LazyLock, time::Duration};
+
+use redis::AsyncCommands as _;
+
+use crate::{
+    submission::{now_millis, Submission},
+    waiter::Constraint,
+};
+
+// Submissions nobody was waiting for, newest at the head
+const BANK: &str = "banked_numbers";
+// Once full, the oldest banked numbers make way for new ones
+const CAPACITY: isize = 1000;
+
+// Drop banked numbers from the old end of the bank until the oldest one is
+// younger than the cutoff, and return how many are left
+static PURGE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local oldest = redis.call('LINDEX', KEYS[1], -1)
+        while oldest and cjson.decode(oldest).submitted_at < tonumber(ARGV[1]) do
+            redis.call('RPOP', KEYS[1])
+            oldest = redis.call('LINDEX', KEYS[1], -1)
+        end
+        return redis.call('LLEN', KEYS[1])
+        ",
+    )
+});
+
+async fn purge(conn: &mut deadpool_redis::Connection, expiry: Duration) -> anyhow::Result<usize> {
+    let cutoff = now_millis().saturating_sub(u64::try_from(expiry.as_millis())?);
+    Ok(PURGE_SCRIPT
+        .key(BANK)
+        .arg(cutoff)
+        .invoke_async(conn)
+        .await?)
+}
+
+/// Keep a submission that nobody was waiting for until someone asks for one
+pub async fn deposit(
+    conn: &mut deadpool_redis::Connection,
+    submission: &Submission,
+) -> anyhow::Result<()> {
+    conn.lpush::<_, _, ()>(BANK, serde_json::to_string(submission)?)
+        .await?;
+    conn.ltrim::<_, ()>(BANK, 0, CAPACITY - 1).await?;
+    Ok(())
+}
+
+/// How many unexpired numbers are in the bank
+pub async fn size(
+    conn: &mut deadpool_redis::Connection,
+    expiry: Duration,
+) -> anyhow::Result<usize> {
+    purge(conn, expiry).await
+}
+
+/// Take the `wanted` oldest banked numbers that satisfy the constraint. Takes
+/// nothing unless there are enough of them.
+pub async fn withdraw(
+    conn: &mut deadpool_redis::Connection,
+    constraint: &Constraint,
+    wanted: usize,
+    expiry: Duration,
+) -> anyhow::Result<Vec<Submission>> {
+    if wanted == 0 || purge(conn, expiry).await? == 0 {
+        return Ok(Vec::new());
+    }
+    let banked: Vec<String> = conn.lrange(BANK, 0, -1).await?;
+
+    let mut taken = Vec::new();
+    for entry in banked.iter().rev() {
+        if taken.len() == wanted {
+            break;
+        }
+        let submission: Submission = serde_json::from_str(entry)?;
+        if !constraint.accepts(&submission.value) {
+            continue;
+        }
+        // Only whoever manages to remove an entry gets to use it
+        let removed: usize = conn.lrem(BANK, 1, entry).await?;
+        if removed == 1 {
+            taken.push((entry, submission));
+        }
+    }
+
+    if taken.len() < wanted {
+        // Not enough to go around, put them back at the old end where they came from
+        if !taken.is_empty() {
+            conn.rpush::<_, _, ()>(
+                BANK,
+                taken
+                    .iter()
+                    .rev()
+                    .map(|(entry, _)| *entry)
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+        }
+        return Ok(Vec::new());
+    }
+    Ok(taken
+        .into_iter()
+        .map(|(_, submission)| submission)
+        .collect())
+}
@@ -0,0 +1,267 @@
+//! Where the moderation rules come from, and keeping them up to date.
+//!
+//! The list is fetched from a local file, an S3 object or a Redis set, and
+//! fetched again every so often or when an admin asks for it. A new list is
+//! swapped in whole, requests are checked against either the old list or the
+//! new one. If the list can't be fetched the previous one stays in place, and
+//! before the first successful fetch nothing is banned. How reloading is going
+//! is part of `/api/stats`, and why it failed is in the logs.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aws_config::BehaviorVersion;
+use redis::AsyncCommands as _;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use tokio::sync::Notify;
+
+use crate::{
+    moderation::{self, Rules},
+    submission::now_millis,
+};
+
+/// Published to ask every instance to reload the list
+pub const RELOAD_CHANNEL: &str = "banned_list_reload";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    S3 { bucket: String, key: String },
+    // Every member of the set is a line of the list
+    Redis(String),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::S3 {
+            bucket: "random-crowdsourced".to_string(),
+            key: "banned_numbers.txt".to_string(),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:").filter(|path| !path.is_empty()) {
+            Ok(Self::File(PathBuf::from(path)))
+        } else if let Some(location) = s.strip_prefix("s3://") {
+            match location.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Self::S3 {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                }),
+                _ => Err(format!("Expected s3://bucket/key, got {s}")),
+            }
+        } else if let Some(key) = s.strip_prefix("redis:").filter(|key| !key.is_empty()) {
+            Ok(Self::Redis(key.to_string()))
+        } else {
+            Err(format!(
+                "Expected file:path, s3://bucket/key or redis:key, got {s}"
+            ))
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::S3 { bucket, key } => write!(f, "s3://{bucket}/{key}"),
+            Self::Redis(key) => write!(f, "redis:{key}"),
+        }
+    }
+}
+
+/// How reloading has gone so far
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ReloadStats {
+    // Successful fetches, and how many of those changed the list
+    pub reloads: u64,
+    pub changes: u64,
+    pub failures: u64,
+    pub rules: usize,
+    // Unix time in milliseconds
+    pub last_reload_ms: Option<u64>,
+    // Hex SHA-256 of the list in use
+    #[serde(skip)]
+    digest: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Reloader {
+    source: Source,
+    redis: Arc<deadpool_redis::Pool>,
+    // Only for S3 sources, so nothing else needs AWS
+    s3: Option<aws_sdk_s3::Client>,
+    requested: Notify,
+    // Held from fetching a list until it's swapped in, so that an older list
+    // can't replace a newer one
+    reloading: tokio::sync::Mutex<()>,
+    stats: Mutex<ReloadStats>,
+}
+
+impl Reloader {
+    pub async fn new(source: Source, redis: Arc<deadpool_redis::Pool>) -> Self {
+        let s3 = match source {
+            Source::S3 { .. } => Some(aws_sdk_s3::Client::new(
+                &aws_config::load_defaults(BehaviorVersion::latest()).await,
+            )),
+            _ => None,
+        };
+        Self {
+            source,
+            redis,
+            s3,
+            requested: Notify::new(),
+            reloading: tokio::sync::Mutex::new(()),
+            stats: Mutex::default(),
+        }
+    }
+
+    async fn fetch(&self) -> anyhow::Result<String> {
+        match &self.source {
+            Source::File(path) => Ok(tokio::fs::read_to_string(path).await?),
+            Source::S3 { bucket, key } => {
+                let s3 = self.s3.as_ref().expect("S3 sources always have a client");
+                let list = s3
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await?
+                    .body
+                    .collect()
+                    .await?
+                    .to_vec();
+                Ok(String::from_utf8(list)?)
+            }
+            Source::Redis(key) => {
+                let mut conn = self.redis.get().await?;
+                let mut lines: Vec<String> = conn.smembers(key).await?;
+                // Sets have no order, but the digest of the list should be stable
+                lines.sort_unstable();
+                Ok(lines.join("\n"))
+            }
+        }
+    }
+
+    /// Fetch the list and swap it in if it changed
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let _reloading = self.reloading.lock().await;
+        let fetched = self.fetch().await.and_then(|list| {
+            let digest = hex::encode(Sha256::digest(&list));
+            let rules =
+                Rules::parse(&list).map_err(|e| anyhow::anyhow!("Invalid banned list: {e}"))?;
+            Ok((digest, rules))
+        });
+
+        let mut stats = self.stats.lock().unwrap();
+        let (digest, rules) = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                stats.failures += 1;
+                return Err(e);
+            }
+        };
+        stats.reloads += 1;
+        stats.last_reload_ms = Some(now_millis());
+        if stats.digest.as_ref() == Some(&digest) {
+            tracing::debug!("Banned list from {} is unchanged", self.source);
+            return Ok(());
+        }
+
+        stats.rules = moderation::replace(rules);
+        stats.changes += 1;
+        stats.digest = Some(digest);
+        tracing::info!(
+            "Loaded {} moderation rules from {} (reload {}, change {})",
+            stats.rules,
+            self.source,
+            stats.reloads,
+            stats.changes
+        );
+        Ok(())
+    }
+
+    /// Reload as soon as possible
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    pub fn stats(&self) -> ReloadStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Reload every period, and whenever a reload is requested
+    pub async fn run(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        // Loaded at startup already
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = self.requested.notified() => {
+                    tracing::info!("Reloading banned list on request");
+                }
+            }
+            if let Err(e) = self.reload().await {
+                tracing::error!("Unable to reload banned list from {}: {e:?}", self.source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!(
+            "file:/etc/banned.txt".parse(),
+            Ok(Source::File(PathBuf::from("/etc/banned.txt")))
+        );
+        assert_eq!(
+            "s3://bucket/lists/banned.txt".parse(),
+            Ok(Source::S3 {
+                bucket: "bucket".to_string(),
+                key: "lists/banned.txt".to_string(),
+            })
+        );
+        assert_eq!(
+            "redis:banned".parse(),
+            Ok(Source::Redis("banned".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        for source in [
+            "",
+            "banned.txt",
+            "file:",
+            "s3://bucket",
+            "s3:///key",
+            "s3://bucket/",
+            "redis:",
+        ] {
+            assert!(source.parse::<Source>().is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn displays_sources_as_parsed() {
+        for source in ["file:banned.txt", "s3://bucket/banned.txt", "redis:banned"] {
+            assert_eq!(source.parse::<Source>().unwrap().to_string(), source);
+        }
+        assert_eq!(Source::default().to_string().parse(), Ok(Source::default()));
+    }
+}
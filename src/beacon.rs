@@ -0,0 +1,351 @@
+//! A public randomness beacon built from crowd submissions, loosely following
+//! the NIST beacon format.
+//!
+//! Every period one instance emits a pulse holding a SHA-512 hash of every
+//! human submission received since the previous pulse. The pulse is signed
+//! with the receipt key over the JSON of every field before
+//! `signature_value`, and its `output_value` is the SHA-512 of that
+//! signature. Each pulse includes the previous pulse's output value, so
+//! pulses can't be changed after the fact without breaking the chain.
+
+use std::{sync::LazyLock, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha512};
+
+use crate::{
+    error::RrgError,
+    receipt::Signer,
+    state::AppState,
+    submission::{now_millis, Submission},
+};
+
+const VERSION: &str = "rrg-beacon-1";
+
+// Submissions since the last pulse
+const CURRENT: &str = "beacon:current";
+// Pulse JSON by index
+const PULSES: &str = "beacon:pulses";
+const LATEST: &str = "beacon:latest";
+// Held by the instance emitting the next pulse
+const LEADER: &str = "beacon:leader";
+
+// Store a pulse and drop the submissions it was made from, unless another
+// instance stored one after the previous pulse first. Submissions recorded
+// while the pulse was made are after the ones it took, so they stay for the
+// next one. Until the pulse is stored nothing is dropped, so none are lost if
+// making it fails.
+static STORE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        if (redis.call('GET', KEYS[3]) or '') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('LTRIM', KEYS[1], ARGV[2], -1)
+        redis.call('HSET', KEYS[2], ARGV[3], ARGV[4])
+        redis.call('SET', KEYS[3], ARGV[3])
+        return 1
+        ",
+    )
+});
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PulseBody {
+    version: String,
+    index: u64,
+    // Unix time in milliseconds
+    timestamp_ms: u64,
+    period_ms: u64,
+    public_key: String,
+    submission_count: usize,
+    // Hex SHA-512 of the submissions as JSON, one per line
+    submissions_hash: String,
+    // All zeros for the first pulse
+    previous_output_value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Pulse {
+    #[serde(flatten)]
+    body: PulseBody,
+    // Hex Ed25519 signature
+    signature_value: String,
+    // Hex SHA-512 of the signature, the random value for the period
+    output_value: String,
+}
+
+/// Remember a human submission for the next pulse
+pub async fn record(
+    conn: &mut deadpool_redis::Connection,
+    submission: &Submission,
+) -> anyhow::Result<()> {
+    conn.rpush::<_, _, ()>(CURRENT, serde_json::to_string(submission)?)
+        .await?;
+    Ok(())
+}
+
+async fn latest(conn: &mut deadpool_redis::Connection) -> anyhow::Result<Option<Pulse>> {
+    let Some(index) = conn.get::<_, Option<u64>>(LATEST).await? else {
+        return Ok(None);
+    };
+    pulse(conn, index).await
+}
+
+async fn pulse(conn: &mut deadpool_redis::Connection, index: u64) -> anyhow::Result<Option<Pulse>> {
+    let pulse: Option<String> = conn.hget(PULSES, index).await?;
+    Ok(pulse
+        .map(|pulse| serde_json::from_str(&pulse))
+        .transpose()?)
+}
+
+impl Pulse {
+    // The pulse after `previous`, or the first one
+    fn next(
+        previous: Option<&Self>,
+        entries: &[String],
+        timestamp_ms: u64,
+        period: Duration,
+        signer: &Signer,
+    ) -> anyhow::Result<Self> {
+        let mut submissions_hash = Sha512::new();
+        for entry in entries {
+            submissions_hash.update(entry.as_bytes());
+            submissions_hash.update(b"\n");
+        }
+
+        let body = PulseBody {
+            version: VERSION.to_string(),
+            index: previous.map_or(0, |previous| previous.body.index + 1),
+            timestamp_ms,
+            period_ms: u64::try_from(period.as_millis())?,
+            public_key: signer.public_key(),
+            submission_count: entries.len(),
+            submissions_hash: hex::encode(submissions_hash.finalize()),
+            previous_output_value: previous
+                .map_or_else(|| "0".repeat(128), |previous| previous.output_value.clone()),
+        };
+        let signature = signer.sign_bytes(&serde_json::to_vec(&body)?);
+        Ok(Self {
+            body,
+            signature_value: hex::encode(signature),
+            output_value: hex::encode(Sha512::digest(signature)),
+        })
+    }
+}
+
+async fn emit(
+    conn: &mut deadpool_redis::Connection,
+    signer: &Signer,
+    period: Duration,
+) -> anyhow::Result<Pulse> {
+    let previous = latest(conn).await?;
+    let entries: Vec<String> = conn.lrange(CURRENT, 0, -1).await?;
+    let pulse = Pulse::next(previous.as_ref(), &entries, now_millis(), period, signer)?;
+
+    let stored: bool = STORE_SCRIPT
+        .key(CURRENT)
+        .key(PULSES)
+        .key(LATEST)
+        .arg(previous.map_or_else(String::new, |previous| previous.body.index.to_string()))
+        .arg(entries.len())
+        .arg(pulse.body.index)
+        .arg(serde_json::to_string(&pulse)?)
+        .invoke_async(conn)
+        .await?;
+    if !stored {
+        anyhow::bail!("Another instance emitted pulse {} first", pulse.body.index);
+    }
+    Ok(pulse)
+}
+
+/// Emit a pulse every period. Safe to run on every instance, only the one that
+/// takes the lead for a period emits its pulse.
+pub async fn run(
+    redis: std::sync::Arc<deadpool_redis::Pool>,
+    signer: std::sync::Arc<Signer>,
+    period: Duration,
+) {
+    let instance = uuid::Uuid::new_v4();
+    let lease = u64::try_from(period.as_millis()).unwrap();
+
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+
+        let mut conn = match redis.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("Unable to get Redis connection for the beacon: {e:?}");
+                continue;
+            }
+        };
+
+        // The lead expires slightly before the next tick so that the same or
+        // another instance can take it then
+        let leader: Result<bool, _> = redis::cmd("SET")
+            .arg(LEADER)
+            .arg(instance)
+            .arg("NX")
+            .arg("PX")
+            .arg(lease.saturating_sub(lease / 10))
+            .query_async::<Option<String>>(&mut conn)
+            .await
+            .map(|set| set.is_some());
+        match leader {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                tracing::error!("Unable to take the lead for the beacon: {e:?}");
+                continue;
+            }
+        }
+
+        match emit(&mut conn, &signer, period).await {
+            Ok(pulse) => tracing::debug!(
+                "Emitted beacon pulse {} from {} submissions",
+                pulse.body.index,
+                pulse.body.submission_count
+            ),
+            Err(e) => tracing::error!("Unable to emit beacon pulse: {e:?}"),
+        }
+    }
+}
+
+#[tracing::instrument]
+async fn get_latest(State(state): State<AppState>) -> Result<impl IntoResponse, RrgError> {
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    let pulse = latest(&mut conn).await?.ok_or(RrgError::NotFound)?;
+    Ok(Json(pulse))
+}
+
+#[tracing::instrument]
+async fn get_pulse(
+    Path(index): Path<u64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RrgError> {
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    let pulse = pulse(&mut conn, index).await?.ok_or(RrgError::NotFound)?;
+    Ok(Json(pulse))
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/latest", get(get_latest))
+        .route("/{index}", get(get_pulse))
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signature, Verifier as _, VerifyingKey};
+
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    fn entries(values: &[&str]) -> Vec<String> {
+        values
+            .iter()
+            .map(|value| {
+                serde_json::to_string(&Submission::new((*value).to_string(), "anon-1".to_string()))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    // What anyone holding a pulse can check with nothing but the pulse
+    fn verifies(pulse: &Pulse) -> bool {
+        let public_key: [u8; 32] = hex::decode(&pulse.body.public_key)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = hex::decode(&pulse.signature_value).unwrap();
+        let Ok(signature) = Signature::from_slice(&signature) else {
+            return false;
+        };
+        VerifyingKey::from_bytes(&public_key)
+            .unwrap()
+            .verify(&serde_json::to_vec(&pulse.body).unwrap(), &signature)
+            .is_ok()
+            && pulse.output_value == hex::encode(Sha512::digest(signature.to_bytes()))
+    }
+
+    #[test]
+    fn starts_the_chain() {
+        let signer = Signer::generate();
+        let entries = entries(&["4", "7"]);
+        let pulse = Pulse::next(None, &entries, 1000, PERIOD, &signer).unwrap();
+
+        assert_eq!(pulse.body.index, 0);
+        assert_eq!(pulse.body.previous_output_value, "0".repeat(128));
+        assert_eq!(pulse.body.period_ms, 60_000);
+        assert_eq!(pulse.body.submission_count, 2);
+        assert_eq!(
+            pulse.body.submissions_hash,
+            hex::encode(Sha512::digest(format!("{}\n{}\n", entries[0], entries[1])))
+        );
+        assert!(verifies(&pulse));
+    }
+
+    #[test]
+    fn chains_pulses() {
+        let signer = Signer::generate();
+        let first = Pulse::next(None, &entries(&["1"]), 1000, PERIOD, &signer).unwrap();
+        let second = Pulse::next(Some(&first), &[], 61_000, PERIOD, &signer).unwrap();
+        let third = Pulse::next(
+            Some(&second),
+            &entries(&["2", "3"]),
+            121_000,
+            PERIOD,
+            &signer,
+        )
+        .unwrap();
+
+        assert_eq!([second.body.index, third.body.index], [1, 2]);
+        assert_eq!(second.body.previous_output_value, first.output_value);
+        assert_eq!(third.body.previous_output_value, second.output_value);
+        assert_eq!(second.body.submission_count, 0);
+        assert_ne!(first.output_value, second.output_value);
+        assert!(verifies(&second) && verifies(&third));
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let signer = Signer::generate();
+        let pulse = Pulse::next(None, &entries(&["5"]), 1000, PERIOD, &signer).unwrap();
+
+        let mut changed = pulse.clone();
+        changed.body.submissions_hash = hex::encode(Sha512::digest(b"6\n"));
+        assert!(!verifies(&changed));
+
+        let mut reindexed = pulse.clone();
+        reindexed.body.index = 1;
+        assert!(!verifies(&reindexed));
+
+        let mut forged =
+            Pulse::next(None, &entries(&["5"]), 1000, PERIOD, &Signer::generate()).unwrap();
+        forged.body.public_key = pulse.body.public_key.clone();
+        assert!(!verifies(&forged));
+    }
+
+    #[test]
+    fn stores_pulses_as_signed() {
+        let signer = Signer::generate();
+        let pulse = Pulse::next(None, &entries(&["8"]), 1000, PERIOD, &signer).unwrap();
+        let stored: Pulse = serde_json::from_str(&serde_json::to_string(&pulse).unwrap()).unwrap();
+
+        assert_eq!(stored.body.index, pulse.body.index);
+        assert_eq!(stored.output_value, pulse.output_value);
+        assert!(verifies(&stored));
+        // Every field is flattened into one object
+        let json = serde_json::to_value(&pulse).unwrap();
+        assert_eq!(json["index"], 0);
+        assert_eq!(json["signature_value"], pulse.signature_value);
+    }
+}
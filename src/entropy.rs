@@ -0,0 +1,171 @@
+//! An entropy pool that turns biased human submissions into uniform bytes.
+//!
+//! Every accepted submission is hashed into the pool state with SHA-512, and
+//! credited with a conservative estimate of how surprising it was given every
+//! submission seen before it, up to a limit for each provider so that nobody
+//! can fill the pool alone. Bytes are only handed out while the estimate
+//! covers them, are derived from the state with SHA-512 in counter mode, and
+//! the state is ratcheted forward afterwards so earlier output can't be
+//! recovered from it.
+
+use std::time::Duration;
+
+use redis::AsyncCommands as _;
+use sha2::{Digest as _, Sha512};
+
+use crate::{
+    optimistic::Update,
+    submission::{now_millis, Submission},
+};
+
+const POOL: &str = "entropy_pool";
+// How often values have been seen, for estimating entropy. Values are counted
+// in a fixed number of buckets by hash so the table can't grow without bound.
+// Values sharing a bucket only look more common, which credits less.
+const FREQUENCIES: &str = "entropy_frequency_buckets";
+const FREQUENCY_BUCKETS: u64 = 1 << 16;
+
+// Credit for a single submission, however unusual it is
+const MAX_BITS_PER_SUBMISSION: f64 = 4.0;
+// Credit for everything one provider submits in a window, so that a single
+// client sending values nobody has tried before can't vouch for the pool
+const MAX_BITS_PER_PROVIDER: f64 = 64.0;
+const CREDIT_WINDOW: Duration = Duration::from_secs(60 * 60);
+// Most that can be saved up, the state can't hold more than this anyway
+const MAX_BITS: f64 = 512.0;
+
+struct Pool {
+    state: Vec<u8>,
+    bits: f64,
+}
+
+async fn read(conn: &mut deadpool_redis::Connection) -> anyhow::Result<Pool> {
+    let (state, bits): (Option<String>, Option<f64>) = redis::cmd("HMGET")
+        .arg(POOL)
+        .arg("state")
+        .arg("bits")
+        .query_async(conn)
+        .await?;
+    Ok(Pool {
+        state: state.map(hex::decode).transpose()?.unwrap_or_default(),
+        bits: bits.unwrap_or(0.0),
+    })
+}
+
+fn write(pool: &Pool) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.hset_multiple(
+        POOL,
+        &[
+            ("state", hex::encode(&pool.state)),
+            ("bits", pool.bits.to_string()),
+        ],
+    );
+    pipe
+}
+
+fn bucket(value: &str) -> u64 {
+    let digest = Sha512::digest(value.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap()) % FREQUENCY_BUCKETS
+}
+
+fn credit_key(provider: &str) -> anyhow::Result<String> {
+    let window = now_millis() / u64::try_from(CREDIT_WINDOW.as_millis())?;
+    Ok(format!("entropy_credit:{provider}:{window}"))
+}
+
+/// Mix an accepted submission into the pool
+pub async fn mix(
+    conn: &mut deadpool_redis::Connection,
+    submission: &Submission,
+) -> anyhow::Result<()> {
+    // How surprising the value is given everything seen so far. Counted before
+    // this submission, so the first time around nothing is credited.
+    let bucket = bucket(&submission.value);
+    let seen: Option<u64> = conn.hget(FREQUENCIES, bucket).await?;
+    let total: u64 = conn.hincr(POOL, "submissions", 1).await?;
+    conn.hincr::<_, _, _, ()>(FREQUENCIES, bucket, 1).await?;
+    #[allow(clippy::cast_precision_loss)]
+    let estimate = ((total as f64) / ((seen.unwrap_or(0) + 1) as f64))
+        .log2()
+        .clamp(0.0, MAX_BITS_PER_SUBMISSION);
+
+    // Whatever the provider has left of their credit for this window
+    let credit_key = credit_key(&submission.provider)?;
+    let credited: Option<f64> = conn.get(&credit_key).await?;
+    let estimate = estimate.min((MAX_BITS_PER_PROVIDER - credited.unwrap_or(0.0)).max(0.0));
+    redis::pipe()
+        .incr(&credit_key, estimate)
+        .ignore()
+        .expire(&credit_key, i64::try_from(CREDIT_WINDOW.as_secs())?)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+
+    let input = serde_json::to_vec(submission)?;
+    let mut update = Update::new(POOL, "mixing into the entropy pool");
+    loop {
+        update.watch(conn).await?;
+        let mut pool = read(conn).await?;
+        pool.state = Sha512::new()
+            .chain_update(b"mix")
+            .chain_update(&pool.state)
+            .chain_update(&input)
+            .finalize()
+            .to_vec();
+        pool.bits = (pool.bits + estimate).min(MAX_BITS);
+        if update.apply(conn, &mut write(&pool)).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// How many bits of entropy the pool is estimated to hold
+pub async fn estimate(conn: &mut deadpool_redis::Connection) -> anyhow::Result<f64> {
+    let bits: Option<f64> = conn.hget(POOL, "bits").await?;
+    Ok(bits.unwrap_or(0.0))
+}
+
+/// Take `n` uniform bytes from the pool, or None if it doesn't hold enough
+/// entropy for them yet
+pub async fn draw(
+    conn: &mut deadpool_redis::Connection,
+    n: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    #[allow(clippy::cast_precision_loss)]
+    let needed = (n * 8) as f64;
+
+    let mut update = Update::new(POOL, "drawing from the entropy pool");
+    loop {
+        update.watch(conn).await?;
+        let mut pool = read(conn).await?;
+        if pool.bits < needed {
+            update.cancel(conn).await?;
+            return Ok(None);
+        }
+
+        let mut bytes = Vec::with_capacity(n);
+        let mut counter = 0_u64;
+        while bytes.len() < n {
+            bytes.extend(
+                Sha512::new()
+                    .chain_update(b"output")
+                    .chain_update(&pool.state)
+                    .chain_update(counter.to_be_bytes())
+                    .finalize(),
+            );
+            counter += 1;
+        }
+        bytes.truncate(n);
+
+        pool.state = Sha512::new()
+            .chain_update(b"ratchet")
+            .chain_update(&pool.state)
+            .finalize()
+            .to_vec();
+        pool.bits -= needed;
+        if update.apply(conn, &mut write(&pool)).await? {
+            return Ok(Some(bytes));
+        }
+    }
+}
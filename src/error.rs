@@ -16,6 +16,15 @@ pub enum RrgError {
     #[error("Not found")]
     NotFound,
 
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
     #[error(transparent)]
     RenderingInternalError(anyhow::Error),
 
@@ -35,6 +44,15 @@ impl IntoResponse for RrgError {
     fn into_response(self) -> Response {
         match self {
             Self::Uuid(_) | Self::ToStr(_) => StatusCode::BAD_REQUEST.into_response(),
+            Self::BadRequest(message) => {
+                (StatusCode::BAD_REQUEST, format!("{message}\n")).into_response()
+            }
+            Self::Forbidden(message) => {
+                (StatusCode::FORBIDDEN, format!("{message}\n")).into_response()
+            }
+            Self::Conflict(message) => {
+                (StatusCode::CONFLICT, format!("{message}\n")).into_response()
+            }
             Self::NotFound => NotFoundTemplate.render().map_or_else(
                 |_| StatusCode::INTERNAL_SERVER_ERROR.into_response(),
                 |body| (StatusCode::NOT_FOUND, Html(body)).into_response(),
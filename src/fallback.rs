@@ -0,0 +1,120 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use rand::Rng as _;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    bank,
+    submission::Submission,
+    waiter::{Constraint, Waiter},
+};
+
+// Provider handle for numbers made up by the server
+pub const PRNG_PROVIDER: &str = "prng";
+
+/// What to do when no human answers a request before its deadline
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackPolicy {
+    /// Give up and tell the client to try again later
+    #[default]
+    Fail,
+    /// Use numbers from the bank, which humans submitted when nobody was
+    /// waiting
+    Pool,
+    /// Use a machine generated number, clearly labelled as such
+    Prng,
+}
+
+impl FromStr for FallbackPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "pool" => Ok(Self::Pool),
+            "prng" => Ok(Self::Prng),
+            other => Err(format!("Unknown fallback policy: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for FallbackPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fail => write!(f, "fail"),
+            Self::Pool => write!(f, "pool"),
+            Self::Prng => write!(f, "prng"),
+        }
+    }
+}
+
+/// What a client is told when the deadline passed and the fallback policy
+/// didn't fill the request either
+#[derive(Serialize, Debug)]
+pub struct Expired {
+    id: Uuid,
+    status: &'static str,
+    error: &'static str,
+    deadline_seconds: u64,
+    fallback: FallbackPolicy,
+}
+
+impl Expired {
+    pub fn new(id: Uuid, deadline: Duration, fallback: FallbackPolicy) -> Self {
+        Self {
+            id,
+            status: "expired",
+            error: "Nobody submitted a number in time",
+            deadline_seconds: deadline.as_secs(),
+            fallback,
+        }
+    }
+}
+
+fn generate(constraint: &Constraint) -> String {
+    let mut rng = rand::thread_rng();
+    if constraint.is_unconstrained() {
+        return rng.gen::<u32>().to_string();
+    }
+
+    // Unbounded ends get a range about as wide as a u32
+    let span = i64::from(u32::MAX);
+    let min = constraint
+        .min
+        .unwrap_or_else(|| constraint.max.map_or(0, |max| max.saturating_sub(span)));
+    let max = constraint.max.unwrap_or_else(|| min.saturating_add(span));
+    // Constraints are checked on creation so that at least one value in range is
+    // allowed
+    loop {
+        let number = rng.gen_range(min..=max);
+        if !constraint.not.contains(&number) {
+            return number.to_string();
+        }
+    }
+}
+
+/// Make up the numbers a waiter is still missing according to the policy.
+/// Returns None if the policy couldn't (or, for `Fail`, won't) provide them
+/// all.
+pub async fn fill(
+    conn: &mut deadpool_redis::Connection,
+    policy: FallbackPolicy,
+    waiter: &Waiter,
+    missing: usize,
+    bank_expiry: Duration,
+) -> anyhow::Result<Option<Vec<Submission>>> {
+    match policy {
+        FallbackPolicy::Fail => Ok(None),
+        FallbackPolicy::Pool => {
+            let banked = bank::withdraw(conn, &waiter.constraint, missing, bank_expiry).await?;
+            Ok((banked.len() == missing).then_some(banked))
+        }
+        FallbackPolicy::Prng => Ok(Some(
+            (0..missing)
+                .map(|_| Submission::new(generate(&waiter.constraint), PRNG_PROVIDER.to_string()))
+                .collect(),
+        )),
+    }
+}
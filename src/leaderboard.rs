@@ -0,0 +1,188 @@
+//! How often each number has been submitted, over several windows of time.
+//!
+//! Alongside the all-time `counts`, every submission is counted in a sorted
+//! set per time bucket, which expires once it falls out of the longest window
+//! it belongs to. A window's leaderboard is the union of its buckets, cached
+//! for a little while since it's expensive to build.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+
+use crate::submission::now_millis;
+
+// Every submission ever
+const ALL_TIME: &str = "counts";
+// How long a window's union of buckets is reused for
+const UNION_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Window {
+    Hour,
+    Day,
+    Week,
+    #[default]
+    AllTime,
+}
+
+impl Window {
+    pub const ALL: [Self; 4] = [Self::Hour, Self::Day, Self::Week, Self::AllTime];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Hour => "past hour",
+            Self::Day => "past day",
+            Self::Week => "past week",
+            Self::AllTime => "all time",
+        }
+    }
+
+    // How wide the buckets that make up the window are in seconds, and how
+    // many of them there are
+    fn buckets(self) -> Option<(u64, u64)> {
+        match self {
+            Self::Hour => Some((5 * 60, 12)),
+            Self::Day => Some((60 * 60, 24)),
+            Self::Week => Some((24 * 60 * 60, 7)),
+            Self::AllTime => None,
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Self::Hour),
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            "all_time" => Ok(Self::AllTime),
+            other => Err(format!("Unknown leaderboard window: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hour => write!(f, "hour"),
+            Self::Day => write!(f, "day"),
+            Self::Week => write!(f, "week"),
+            Self::AllTime => write!(f, "all_time"),
+        }
+    }
+}
+
+fn bucket_key(width: u64, index: u64) -> String {
+    format!("counts:{width}:{index}")
+}
+
+/// Count a number submitted at `now`, in seconds, on every leaderboard
+pub fn record(pipe: &mut redis::Pipeline, value: &str, now: u64) -> anyhow::Result<()> {
+    pipe.zincr(ALL_TIME, value, 1).ignore();
+    for window in Window::ALL {
+        if let Some((width, count)) = window.buckets() {
+            let index = now / width;
+            let key = bucket_key(width, index);
+            // Gone once it's no longer part of the window
+            let expires_at = (index + 1 + count) * width;
+            pipe.zincr(&key, value, 1)
+                .ignore()
+                .expire_at(&key, i64::try_from(expires_at)?)
+                .ignore();
+        }
+    }
+    Ok(())
+}
+
+/// One page of a leaderboard
+#[derive(Debug)]
+pub struct Page {
+    // Most submitted first, with how often they were submitted
+    pub entries: Vec<(String, u64)>,
+    // How many different numbers are on the whole leaderboard
+    pub total: usize,
+}
+
+/// Where a page of `size` entries starts, counting pages from 1. Fails for a
+/// page that doesn't exist or is further along than any leaderboard can reach.
+pub fn offset(page: usize, size: usize) -> Result<usize, &'static str> {
+    let out_of_range = "page is out of range";
+    let offset = page
+        .checked_sub(1)
+        .ok_or("page starts from 1")?
+        .checked_mul(size)
+        .ok_or(out_of_range)?;
+    // Redis ranks are signed, and the whole page has to fit
+    offset
+        .checked_add(size)
+        .and_then(|end| isize::try_from(end).ok())
+        .ok_or(out_of_range)?;
+    Ok(offset)
+}
+
+/// The `size` numbers submitted most often during the window, skipping the
+/// first `offset`
+pub async fn top(
+    conn: &mut deadpool_redis::Connection,
+    window: Window,
+    offset: usize,
+    size: usize,
+) -> anyhow::Result<Page> {
+    let key = if let Some((width, count)) = window.buckets() {
+        let union = format!("leaderboard:{window}");
+        if !conn.exists::<_, bool>(&union).await? {
+            let current = now_millis() / 1000 / width;
+            let buckets = (0..count)
+                .map(|age| bucket_key(width, current.saturating_sub(age)))
+                .collect::<Vec<_>>();
+            redis::pipe()
+                .atomic()
+                .zunionstore(&union, &buckets)
+                .ignore()
+                .expire(&union, i64::try_from(UNION_TTL.as_secs())?)
+                .ignore()
+                .query_async::<()>(conn)
+                .await?;
+        }
+        union
+    } else {
+        ALL_TIME.to_string()
+    };
+
+    let start = isize::try_from(offset)?;
+    let stop = start
+        .checked_add(isize::try_from(size)?)
+        .ok_or_else(|| anyhow::anyhow!("Page past the end of any leaderboard"))?
+        - 1;
+    let (entries, total) = redis::pipe()
+        .zrevrange_withscores(&key, start, stop)
+        .zcard(&key)
+        .query_async(conn)
+        .await?;
+    Ok(Page { entries, total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_where_pages_start() {
+        assert_eq!(offset(1, 10), Ok(0));
+        assert_eq!(offset(3, 10), Ok(20));
+        assert!(offset(0, 10).is_err());
+    }
+
+    #[test]
+    fn refuses_pages_out_of_range() {
+        assert!(offset(usize::MAX, 10).is_err());
+        assert!(offset(usize::MAX / 10, 10).is_err());
+        let last = isize::MAX.unsigned_abs();
+        assert_eq!(offset(last, 1), Ok(last - 1));
+        assert!(offset(last + 1, 1).is_err());
+    }
+}
@@ -1,13 +1,31 @@
 mod api;
+mod bank;
+mod banned_list;
+mod beacon;
+mod entropy;
 mod error;
+mod fallback;
+mod leaderboard;
 mod middleware;
+mod moderation;
+mod optimistic;
+mod priority;
+mod provider;
+mod quality;
+mod rate_limit;
+mod receipt;
+mod request_type;
 mod site;
+mod sse;
 mod state;
+mod stats;
+mod submission;
+mod waiter;
+mod webhook;
 mod websocket;
 
 use core::panic;
 use std::{
-    collections::HashSet,
     net::SocketAddr,
     str::FromStr,
     sync::{Arc, Mutex},
@@ -15,20 +33,26 @@ use std::{
 };
 
 use anyhow::Result;
-use aws_config::BehaviorVersion;
-use axum::Router;
+use axum::{http::HeaderName, Router};
+use banned_list::{Reloader, Source};
 use deadpool_redis::Runtime;
 use error::RrgError;
+use fallback::FallbackPolicy;
 use futures_util::StreamExt as _;
-use middleware::{MakeRequestUuidV7, SentryReportRequestInfoLayer};
+use middleware::{MakeRequestUuidV7, RateLimitLayer, SentryReportRequestInfoLayer};
+use priority::ApiKeys;
+use provider::CookieKey;
+use rate_limit::RateLimits;
+use receipt::Signer;
 use rinja::Template;
 use secrecy::{ExposeSecret as _, SecretString};
 use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
-use state::{AppState, StateUpdate, BANNED_NUMBERS};
+use state::{AppState, StateUpdate};
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{
     limit::RequestBodyLimitLayer,
+    sensitive_headers::SetSensitiveRequestHeadersLayer,
     services::ServeDir,
     timeout::TimeoutLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
@@ -37,6 +61,7 @@ use tower_http::{
 use tracing::Level;
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 use uuid::Uuid;
+use waiter::Waiter;
 
 struct Config {
     log_level: Option<tracing::metadata::Level>,
@@ -44,8 +69,25 @@ struct Config {
     error_sample_rate: Option<f32>,
     broadcast_capacity: Option<usize>,
     request_timeout_seconds: Option<Duration>,
+    max_wait_seconds: Option<Duration>,
+    ticket_timeout_seconds: Option<Duration>,
+    ticket_retention_seconds: Option<Duration>,
+    resume_grace_seconds: Option<Duration>,
+    fallback_policy: Option<FallbackPolicy>,
+    bank_expiry_seconds: Option<Duration>,
+    beacon_period_seconds: Option<Duration>,
+    leaderboard_size: Option<usize>,
+    api_keys: Option<ApiKeys>,
+    rate_limits: Option<RateLimits>,
+    banned_list_source: Option<Source>,
+    banned_list_reload_seconds: Option<Duration>,
+    behind_fly_proxy: Option<bool>,
 
     sentry_dsn: Option<SecretString>,
+    handle_salt: Option<SecretString>,
+    signing_key: Option<SecretString>,
+    cookie_key: Option<SecretString>,
+    admin_token: Option<SecretString>,
     redis_url: String,
 }
 
@@ -94,7 +136,129 @@ impl Config {
                 })
                 .map(Duration::from_secs),
 
+            max_wait_seconds: std::env::var("RRG_MAX_WAIT_SECONDS")
+                .ok()
+                .map(|max_wait| {
+                    max_wait
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Invalid maximum wait: {max_wait}"))
+                })
+                .map(Duration::from_secs),
+
+            ticket_timeout_seconds: std::env::var("RRG_TICKET_TIMEOUT_SECONDS")
+                .ok()
+                .map(|timeout| {
+                    timeout
+                        .parse()
+                        .ok()
+                        .filter(|&timeout| timeout > 0)
+                        .unwrap_or_else(|| panic!("Invalid ticket timeout: {timeout}"))
+                })
+                .map(Duration::from_secs),
+
+            ticket_retention_seconds: std::env::var("RRG_TICKET_RETENTION_SECONDS")
+                .ok()
+                .map(|retention| {
+                    retention
+                        .parse()
+                        .ok()
+                        .filter(|&retention| retention > 0)
+                        .unwrap_or_else(|| panic!("Invalid ticket retention: {retention}"))
+                })
+                .map(Duration::from_secs),
+
+            resume_grace_seconds: std::env::var("RRG_RESUME_GRACE_SECONDS")
+                .ok()
+                .map(|grace| {
+                    grace
+                        .parse()
+                        .ok()
+                        .filter(|&grace| grace > 0)
+                        .unwrap_or_else(|| panic!("Invalid resume grace period: {grace}"))
+                })
+                .map(Duration::from_secs),
+
+            fallback_policy: std::env::var("RRG_FALLBACK_POLICY").ok().map(|policy| {
+                policy
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid fallback policy: {policy}"))
+            }),
+
+            bank_expiry_seconds: std::env::var("RRG_BANK_EXPIRY_SECONDS")
+                .ok()
+                .map(|expiry| {
+                    expiry
+                        .parse()
+                        .ok()
+                        .filter(|&expiry| expiry > 0)
+                        .unwrap_or_else(|| panic!("Invalid bank expiry: {expiry}"))
+                })
+                .map(Duration::from_secs),
+
+            beacon_period_seconds: std::env::var("RRG_BEACON_PERIOD_SECONDS")
+                .ok()
+                .map(|period| {
+                    period
+                        .parse()
+                        .ok()
+                        .filter(|&period| period > 0)
+                        .unwrap_or_else(|| panic!("Invalid beacon period: {period}"))
+                })
+                .map(Duration::from_secs),
+
+            leaderboard_size: std::env::var("RRG_LEADERBOARD_SIZE").ok().map(|size| {
+                size.parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .unwrap_or_else(|| panic!("Invalid leaderboard size: {size}"))
+            }),
+
+            api_keys: std::env::var("RRG_API_KEYS").ok().map(|keys| {
+                ApiKeys::parse(&keys).unwrap_or_else(|e| panic!("Invalid RRG_API_KEYS: {e}"))
+            }),
+
+            rate_limits: std::env::var("RRG_RATE_LIMITS").ok().map(|limits| {
+                RateLimits::parse(&limits)
+                    .unwrap_or_else(|e| panic!("Invalid RRG_RATE_LIMITS: {e}"))
+            }),
+
+            banned_list_source: std::env::var("RRG_BANNED_LIST_SOURCE").ok().map(|source| {
+                source
+                    .parse()
+                    .unwrap_or_else(|e| panic!("Invalid RRG_BANNED_LIST_SOURCE: {e}"))
+            }),
+
+            banned_list_reload_seconds: std::env::var("RRG_BANNED_LIST_RELOAD_SECONDS")
+                .ok()
+                .map(|period| {
+                    period
+                        .parse()
+                        .ok()
+                        .filter(|&period| period > 0)
+                        .unwrap_or_else(|| panic!("Invalid banned list reload period: {period}"))
+                })
+                .map(Duration::from_secs),
+
+            behind_fly_proxy: std::env::var("RRG_BEHIND_FLY_PROXY").ok().map(|behind| {
+                behind
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid RRG_BEHIND_FLY_PROXY: {behind}"))
+            }),
+
             sentry_dsn: std::env::var("SENTRY_DSN").ok().map(SecretString::from),
+            handle_salt: std::env::var("RRG_HANDLE_SALT")
+                .ok()
+                .map(SecretString::from),
+
+            signing_key: std::env::var("RRG_SIGNING_KEY")
+                .ok()
+                .map(SecretString::from),
+
+            cookie_key: std::env::var("RRG_COOKIE_KEY").ok().map(SecretString::from),
+
+            admin_token: std::env::var("RRG_ADMIN_TOKEN")
+                .ok()
+                .map(SecretString::from),
 
             redis_url: std::env::var("REDIS_URL").expect("Missing environment variable REDIS_URL"),
         }
@@ -137,29 +301,50 @@ fn main() -> Result<()> {
 
 #[allow(clippy::too_many_lines)]
 async fn run(config: Config) -> Result<()> {
-    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let s3 = aws_sdk_s3::Client::new(&aws_config);
-    let banned_numbers = s3
-        .get_object()
-        .bucket("random-crowdsourced")
-        .key("banned_numbers.txt")
-        .send()
-        .await?
-        .body
-        .collect()
-        .await?
-        .to_vec();
-    BANNED_NUMBERS
-        .set(
-            String::from_utf8(banned_numbers)?
-                .lines()
-                .map(str::to_owned)
-                .collect::<HashSet<_>>(),
-        )
-        .unwrap();
-
     let callback_map = Arc::new(Mutex::new(state::CallbackMap::new()));
 
+    // Without a configured salt, handles are still anonymous but differ between
+    // instances
+    let handle_salt = Arc::new(config.handle_salt.unwrap_or_else(|| {
+        tracing::warn!("RRG_HANDLE_SALT is not set, generating a random one");
+        SecretString::from(Uuid::new_v4().to_string())
+    }));
+
+    let request_timeout = config
+        .request_timeout_seconds
+        .unwrap_or(Duration::from_secs(30));
+    let max_wait = config
+        .max_wait_seconds
+        .unwrap_or(Duration::from_secs(5 * 60))
+        .max(request_timeout);
+
+    // Without a configured key, receipts can only be verified while this instance
+    // is running
+    let signer = Arc::new(config.signing_key.map_or_else(
+        || {
+            tracing::warn!("RRG_SIGNING_KEY is not set, generating a random one");
+            Signer::generate()
+        },
+        |key| {
+            Signer::from_hex(key.expose_secret())
+                .unwrap_or_else(|e| panic!("Invalid RRG_SIGNING_KEY: {e}"))
+        },
+    ));
+    tracing::info!("Signing receipts with public key {}", signer.public_key());
+
+    // Without a configured key, providers lose their handles whenever the
+    // instance restarts and can't keep them across instances
+    let cookie_key = config.cookie_key.map_or_else(
+        || {
+            tracing::warn!("RRG_COOKIE_KEY is not set, generating a random one");
+            CookieKey::generate()
+        },
+        |key| {
+            CookieKey::from_hex(key.expose_secret())
+                .unwrap_or_else(|e| panic!("Invalid RRG_COOKIE_KEY: {e}"))
+        },
+    );
+
     let tx = tokio::sync::broadcast::Sender::new(config.broadcast_capacity.unwrap_or(10));
     let state_updates = Arc::new(tx.clone());
 
@@ -167,6 +352,14 @@ async fn run(config: Config) -> Result<()> {
     let deadpool_config = deadpool_redis::Config::from_url(redis_url.as_str());
     let redis = Arc::new(deadpool_config.create_pool(Some(Runtime::Tokio1))?);
 
+    // Keep going without a banned list rather than not at all, it's retried on
+    // every reload
+    let banned_list =
+        Arc::new(Reloader::new(config.banned_list_source.unwrap_or_default(), redis.clone()).await);
+    if let Err(e) = banned_list.reload().await {
+        tracing::error!("Unable to load banned list, starting without one: {e:?}");
+    }
+
     let pubsub_task = {
         let (mut sink, mut stream) = redis::Client::open(redis_url.as_str())
             .unwrap()
@@ -176,18 +369,19 @@ async fn run(config: Config) -> Result<()> {
             .split();
         sink.subscribe("callbacks").await?;
         sink.subscribe("state_updates").await?;
+        sink.subscribe(banned_list::RELOAD_CHANNEL).await?;
 
         let callback_map = callback_map.clone();
+        let banned_list = banned_list.clone();
 
         tokio::task::spawn(async move {
             while let Some(msg) = stream.next().await {
                 match msg.get_channel_name() {
                     "callbacks" => {
-                        let (callback_id, random_number) =
+                        let (callback_id, message): (Uuid, state::Callback) =
                             serde_json::from_slice(msg.get_payload_bytes()).unwrap();
-                        let callback = callback_map.lock().unwrap().remove(&callback_id);
-                        if let Some(callback) = callback {
-                            if callback.send(random_number).is_err() {
+                        if let Some(callback) = callback_map.lock().unwrap().get(&callback_id) {
+                            if callback.send(message).is_err() {
                                 tracing::debug!(
                                     "{callback_id} dropped before receiving random number"
                                 );
@@ -207,19 +401,24 @@ async fn run(config: Config) -> Result<()> {
                             tracing::debug!("Broadcasting state update: {state_update:?}");
 
                             let list_item = match state_update {
-                                StateUpdate::Added(guid) => ListItemFragment {
-                                    client: guid,
-                                    delete: false,
-                                }
-                                .render()
-                                .unwrap(),
+                                StateUpdate::Added(waiter) => ListItemFragment {
+                                    id: waiter.id,
+                                    client: Some(waiter),
+                                    replace: false,
+                                },
+                                StateUpdate::Updated(waiter) => ListItemFragment {
+                                    id: waiter.id,
+                                    client: Some(waiter),
+                                    replace: true,
+                                },
                                 StateUpdate::Removed(guid) => ListItemFragment {
-                                    client: guid,
-                                    delete: true,
-                                }
-                                .render()
-                                .unwrap(),
-                            };
+                                    id: guid,
+                                    client: None,
+                                    replace: false,
+                                },
+                            }
+                            .render()
+                            .unwrap();
 
                             tx.send(list_item).expect("Receiver unexpectedly dropped");
                         } else {
@@ -229,22 +428,60 @@ async fn run(config: Config) -> Result<()> {
                         }
                     }
 
+                    banned_list::RELOAD_CHANNEL => banned_list.request(),
+
                     c => panic!("unknown channel: {c}"),
                 }
             }
         })
     };
 
+    let webhook_task = tokio::task::spawn(webhook::run_outbox(redis.clone()));
+    let beacon_task = tokio::task::spawn(beacon::run(
+        redis.clone(),
+        signer.clone(),
+        config
+            .beacon_period_seconds
+            .unwrap_or(Duration::from_secs(60)),
+    ));
+
+    let banned_list_task = tokio::task::spawn(
+        banned_list.clone().run(
+            config
+                .banned_list_reload_seconds
+                .unwrap_or(Duration::from_secs(5 * 60)),
+        ),
+    );
+
+    // Only trust the proxy's word for who the client is when there is a proxy
+    let behind_fly_proxy = config.behind_fly_proxy.unwrap_or(false);
+    let api_keys = Arc::new(config.api_keys.unwrap_or_default());
+    let rate_limit = RateLimitLayer::new(
+        redis.clone(),
+        Arc::new(config.rate_limits.unwrap_or_default()),
+        api_keys.clone(),
+        behind_fly_proxy,
+    );
+
     // Initialize routes
     let app = Router::new()
         .merge(site::routes())
-        .nest("/api", api::routes())
+        .nest("/api", api::routes().layer(rate_limit))
         .nest("/ws", websocket::routes())
         .nest_service("/static", ServeDir::new("assets/static"))
         .fallback(|| async { RrgError::NotFound })
         .layer(
             ServiceBuilder::new()
                 .set_x_request_id(MakeRequestUuidV7)
+                // Kept out of the logs and Sentry, which is everything below
+                .layer(SetSensitiveRequestHeadersLayer::new(
+                    [
+                        api::ADMIN_TOKEN_HEADER,
+                        api::REQUEST_SECRET_HEADER,
+                        priority::API_KEY_HEADER,
+                    ]
+                    .map(HeaderName::from_static),
+                ))
                 .layer(NewSentryLayer::new_from_top())
                 .layer(SentryHttpLayer::with_transaction())
                 .layer(SentryReportRequestInfoLayer)
@@ -256,16 +493,37 @@ async fn run(config: Config) -> Result<()> {
                 .propagate_x_request_id()
                 // Very generous limit for submit requests
                 .layer(RequestBodyLimitLayer::new(4096))
-                .layer(TimeoutLayer::new(
-                    config
-                        .request_timeout_seconds
-                        .unwrap_or(Duration::from_secs(30)),
-                )),
+                // Requests waiting for numbers time out on their own to apply a fallback,
+                // this is only a backstop
+                .layer(TimeoutLayer::new(max_wait + Duration::from_secs(5))),
         )
         .with_state(AppState {
             redis,
             callback_map,
             state_updates,
+            handle_salt,
+            behind_fly_proxy,
+            signer,
+            cookie_key,
+            request_timeout,
+            max_wait,
+            fallback_policy: config.fallback_policy.unwrap_or_default(),
+            api_keys,
+            leaderboard_size: config.leaderboard_size.unwrap_or(10),
+            bank_expiry: config
+                .bank_expiry_seconds
+                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            ticket_timeout: config
+                .ticket_timeout_seconds
+                .unwrap_or(Duration::from_secs(60 * 60)),
+            ticket_retention: config
+                .ticket_retention_seconds
+                .unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            resume_grace: config
+                .resume_grace_seconds
+                .unwrap_or(Duration::from_secs(30)),
+            banned_list,
+            admin_token: config.admin_token.map(Arc::new),
         });
 
     // Listen and serve
@@ -279,6 +537,9 @@ async fn run(config: Config) -> Result<()> {
 
     // TODO clean shutdown
     pubsub_task.abort();
+    webhook_task.abort();
+    beacon_task.abort();
+    banned_list_task.abort();
 
     Ok(())
 }
@@ -286,6 +547,9 @@ async fn run(config: Config) -> Result<()> {
 #[derive(Template)]
 #[template(path = "list_item.html")]
 struct ListItemFragment {
-    client: Uuid,
-    delete: bool,
+    id: Uuid,
+    // None if the waiter should be removed from the list
+    client: Option<Waiter>,
+    // Swap out an existing list item rather than adding a new one
+    replace: bool,
 }
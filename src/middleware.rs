@@ -1,15 +1,31 @@
 use std::{
+    convert::Infallible,
     future::Future,
+    net::SocketAddr,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use axum::http::{header, Request, Response, StatusCode};
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request, Response, StatusCode},
+    response::{Html, IntoResponse as _},
+};
+use rinja::Template as _;
 use sentry::protocol::SpanStatus;
 use tower::{Layer, Service};
 use tower_http::request_id::{MakeRequestId, RequestId};
 use uuid::Uuid;
 
+use crate::{
+    api::InputFieldTemplate,
+    priority::{self, ApiKeys},
+    rate_limit::{self, Identity, RateLimits},
+    submission,
+};
+
 #[derive(Clone, Copy)]
 pub struct MakeRequestUuidV7;
 impl MakeRequestId for MakeRequestUuidV7 {
@@ -125,3 +141,141 @@ where
         })
     }
 }
+
+/// Rate limits requests to the API by route, see [`rate_limit`]
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    redis: Arc<deadpool_redis::Pool>,
+    limits: Arc<RateLimits>,
+    api_keys: Arc<ApiKeys>,
+    behind_fly_proxy: bool,
+}
+
+impl RateLimitLayer {
+    pub fn new(
+        redis: Arc<deadpool_redis::Pool>,
+        limits: Arc<RateLimits>,
+        api_keys: Arc<ApiKeys>,
+        behind_fly_proxy: bool,
+    ) -> Self {
+        Self {
+            redis,
+            limits,
+            api_keys,
+            behind_fly_proxy,
+        }
+    }
+
+    // How long the client has to wait before the request is allowed, if at all
+    async fn check(&self, route: &str, identity: &Identity) -> Option<Duration> {
+        let mut conn = match self.redis.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("Unable to get Redis connection for rate limiting: {e:?}");
+                return None;
+            }
+        };
+        // Better to let everyone through than nobody while Redis is struggling
+        rate_limit::take(&mut conn, &self.limits, route, identity)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Unable to check rate limit: {e:?}");
+                None
+            })
+    }
+
+    // Known API keys are limited by key, anything else by address
+    fn identity<B>(&self, request: &Request<B>) -> Option<Identity> {
+        let headers = request.headers();
+        if let Some(key) = headers
+            .get(priority::API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .filter(|key| self.api_keys.get(key).is_some())
+        {
+            return Some(Identity::ApiKey(key.to_string()));
+        }
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| {
+                Identity::Ip(submission::client_ip(*addr, headers, self.behind_fly_proxy))
+            })
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+fn too_many_requests(route: &str, headers: &HeaderMap, wait: Duration) -> axum::response::Response {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let retry_after = [(header::RETRY_AFTER, seconds.to_string())];
+
+    // The submit form shows why it didn't go through
+    if route == "submit" && headers.contains_key("hx-request") {
+        let context = format!("Slow down! Try again in {seconds} seconds.");
+        if let Ok(body) = (InputFieldTemplate {
+            classes: r#"class="error" classes="remove error""#,
+            context: &context,
+        })
+        .render()
+        {
+            return (StatusCode::TOO_MANY_REQUESTS, retry_after, Html(body)).into_response();
+        }
+    }
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        retry_after,
+        format!("Too many requests, try again in {seconds} seconds\n"),
+    )
+        .into_response()
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RateLimitService<S>
+where
+    S: Service<Request<ReqBody>, Response = axum::response::Response, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        // The service that was polled ready handles the request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let route = rate_limit::route(request.method(), request.uri().path());
+            if let Some(identity) = layer.identity(&request) {
+                if let Some(wait) = layer.check(&route, &identity).await {
+                    tracing::debug!("Rate limited a request to {route} for {wait:?}");
+                    return Ok(too_many_requests(&route, request.headers(), wait));
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
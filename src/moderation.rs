@@ -0,0 +1,360 @@
+//! Rules for what people aren't allowed to submit, and what to do about it.
+//!
+//! Text is normalised before it's checked, so that rules can't be dodged with
+//! full-width digits, spaces, capitals or letters that look like digits. Rules
+//! are loaded from a list with one rule per line:
+//!
+//! ```text
+//! # Comments start with a # at the start of a line or after a space
+//! 69                      # a number, matched by value so 069 and 69.0 count
+//! naughty                 # a whole word or the whole text
+//! contains:420            # anywhere in the text
+//! regex:^6+9+$            # against the normalised text
+//! number:1337             # explicitly by value
+//! flag contains:666       # any rule can be given an action, reject by default
+//! shadow regex:^8008+5$
+//! ```
+
+use std::sync::{Arc, LazyLock, RwLock};
+
+use regex::Regex;
+use serde::Serialize;
+use unicode_normalization::UnicodeNormalization as _;
+
+use crate::submission::now_millis;
+
+// Swapped out whole when the list is reloaded
+static RULES: LazyLock<RwLock<Arc<Rules>>> = LazyLock::new(RwLock::default);
+
+/// What to do with text that matches a rule, least severe first
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Allow,
+    /// Let it through, but keep note of it for someone to look at
+    Flag,
+    /// Pretend to accept it, but throw it away
+    Shadow,
+    /// Refuse it
+    Reject,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Exact(String),
+    Contains(String),
+    Regex(Regex),
+    Number(f64),
+}
+
+#[derive(Debug)]
+struct Rule {
+    // The line it was parsed from, to say which rules matched
+    source: String,
+    matcher: Matcher,
+    action: Action,
+}
+
+/// The outcome of checking some text against the rules
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Verdict {
+    // The most severe action of any matching rule
+    pub action: Action,
+    pub rules: Vec<String>,
+}
+
+// Lowercase with no whitespace, after NFKC has turned full-width, circled,
+// superscript and similar digits into plain ones
+fn normalise(text: &str) -> String {
+    text.nfkc()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Letters that are commonly swapped in for digits. Some depend on case, so
+// this runs before lowercasing.
+fn deconfuse(text: &str) -> String {
+    let digits = text
+        .nfkc()
+        .map(|c| match c {
+            'O' | 'o' | 'Ο' | 'ο' | 'О' | 'о' => '0',
+            'I' | 'i' | 'l' => '1',
+            'Z' | 'z' => '2',
+            'E' | 'e' | 'З' | 'з' => '3',
+            'A' | 'a' => '4',
+            'S' | 's' => '5',
+            'b' | 'G' | 'б' => '6',
+            'T' | 't' => '7',
+            'B' => '8',
+            'g' | 'q' => '9',
+            c => c,
+        })
+        .collect::<String>();
+    normalise(&digits)
+}
+
+fn number(text: &str) -> Option<f64> {
+    text.strip_prefix('+')
+        .unwrap_or(text)
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+}
+
+// Every way of reading some text that rules are checked against
+struct Forms {
+    whole: [String; 2],
+    words: Vec<String>,
+}
+
+impl Forms {
+    fn new(text: &str) -> Self {
+        Self {
+            whole: [normalise(text), deconfuse(text)],
+            words: text
+                .split_whitespace()
+                .flat_map(|word| [normalise(word), deconfuse(word)])
+                .collect(),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &String> {
+        self.whole.iter().chain(&self.words)
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Self, String> {
+        let (action, rule) = match line.split_once(char::is_whitespace) {
+            Some(("reject", rule)) => (Action::Reject, rule.trim()),
+            Some(("shadow", rule)) => (Action::Shadow, rule.trim()),
+            Some(("flag", rule)) => (Action::Flag, rule.trim()),
+            _ => (Action::Reject, line),
+        };
+
+        // A matcher that matches everything would turn every submission away
+        let matcher = match rule.split_once(':') {
+            Some(("contains", text)) => {
+                let text = normalise(text);
+                if text.is_empty() {
+                    return Err("Nothing to look for after contains:".to_string());
+                }
+                Matcher::Contains(text)
+            }
+            Some(("regex", pattern)) => {
+                let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
+                if regex.is_match("") {
+                    return Err(format!("Pattern matches empty text: {pattern}"));
+                }
+                Matcher::Regex(regex)
+            }
+            Some(("number", value)) => Matcher::Number(
+                number(&normalise(value)).ok_or_else(|| format!("Not a number: {value}"))?,
+            ),
+            _ => {
+                let text = normalise(rule);
+                number(&text).map_or(Matcher::Exact(text), Matcher::Number)
+            }
+        };
+
+        Ok(Self {
+            source: line.to_string(),
+            matcher,
+            action,
+        })
+    }
+
+    fn matches(&self, forms: &Forms) -> bool {
+        match &self.matcher {
+            Matcher::Exact(text) => forms.all().any(|form| form == text),
+            Matcher::Contains(text) => forms.whole.iter().any(|form| form.contains(text)),
+            Matcher::Regex(regex) => forms.whole.iter().any(|form| regex.is_match(form)),
+            #[allow(clippy::float_cmp)]
+            Matcher::Number(value) => forms.all().any(|form| number(form) == Some(*value)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn parse(list: &str) -> Result<Self, String> {
+        list.lines()
+            .enumerate()
+            .filter_map(|(i, line)| {
+                let line = line.split_once(" #").map_or(line, |(rule, _)| rule).trim();
+                (!line.is_empty() && !line.starts_with('#')).then_some((i, line))
+            })
+            .map(|(i, line)| Rule::parse(line).map_err(|e| format!("line {}: {e}", i + 1)))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn check(&self, text: &str) -> Verdict {
+        let forms = Forms::new(text);
+        let mut verdict = Verdict::default();
+        for rule in self.0.iter().filter(|rule| rule.matches(&forms)) {
+            verdict.action = verdict.action.max(rule.action);
+            verdict.rules.push(rule.source.clone());
+        }
+        verdict
+    }
+}
+
+/// Check text against the loaded rules
+pub fn check(text: &str) -> Verdict {
+    let rules = RULES.read().unwrap().clone();
+    rules.check(text)
+}
+
+/// Start checking against new rules, and return how many there are
+pub fn replace(rules: Rules) -> usize {
+    let count = rules.0.len();
+    *RULES.write().unwrap() = Arc::new(rules);
+    count
+}
+
+// Flagged submissions for someone to look at, newest first
+const FLAGGED: &str = "moderation_flagged";
+const FLAGGED_CAPACITY: isize = 1000;
+
+/// Keep note of text that was let through but flagged by a rule
+pub async fn flag(
+    conn: &mut deadpool_redis::Connection,
+    text: &str,
+    verdict: &Verdict,
+) -> anyhow::Result<()> {
+    tracing::warn!("Flagged text matching {:?}", verdict.rules);
+    sentry::configure_scope(|scope| scope.set_tag("flagged", "true"));
+
+    let entry = serde_json::json!({
+        "text": text,
+        "rules": verdict.rules,
+        "flagged_at": now_millis(),
+    });
+    redis::pipe()
+        .lpush(FLAGGED, entry.to_string())
+        .ignore()
+        .ltrim(FLAGGED, 0, FLAGGED_CAPACITY - 1)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(list: &str) -> Rules {
+        Rules::parse(list).unwrap()
+    }
+
+    fn action(list: &str, text: &str) -> Action {
+        rules(list).check(text).action
+    }
+
+    #[test]
+    fn normalises_text() {
+        assert_eq!(normalise(" Hello\tWorld "), "helloworld");
+        assert_eq!(normalise("６ ９"), "69");
+        assert_eq!(normalise("①²"), "12");
+    }
+
+    #[test]
+    fn deconfuses_text() {
+        assert_eq!(deconfuse("bOOBS"), "60085");
+        assert_eq!(deconfuse("l33t"), "1337");
+        assert_eq!(deconfuse("Зо"), "30");
+        assert_eq!(deconfuse("6 g"), "69");
+    }
+
+    #[test]
+    fn matches_exactly() {
+        let list = "naughty";
+        assert_eq!(action(list, "NAUGHTY"), Action::Reject);
+        assert_eq!(action(list, "very naughty words"), Action::Reject);
+        assert_eq!(action(list, "n a u g h t y"), Action::Reject);
+        assert_eq!(action(list, "naughtyish"), Action::Allow);
+    }
+
+    #[test]
+    fn matches_anywhere() {
+        let list = "contains:420";
+        assert_eq!(action(list, "1420"), Action::Reject);
+        assert_eq!(action(list, "x4 20y"), Action::Reject);
+        assert_eq!(action(list, "４2O"), Action::Reject);
+        assert_eq!(action(list, "402"), Action::Allow);
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let list = "regex:^6+9+$";
+        assert_eq!(action(list, "669"), Action::Reject);
+        assert_eq!(action(list, "66 99"), Action::Reject);
+        assert_eq!(action(list, "bbq"), Action::Reject);
+        assert_eq!(action(list, "6969"), Action::Allow);
+    }
+
+    #[test]
+    fn matches_numbers() {
+        for list in ["69", "number:69", "number:069"] {
+            for text in [
+                "69",
+                "069",
+                "69.0",
+                "+69",
+                "6 9",
+                "６９",
+                "6g",
+                "the 69 thing",
+            ] {
+                assert_eq!(action(list, text), Action::Reject, "{list} {text}");
+            }
+            for text in ["690", "6.9", "69th", "-69"] {
+                assert_eq!(action(list, text), Action::Allow, "{list} {text}");
+            }
+        }
+    }
+
+    #[test]
+    fn takes_the_most_severe_action() {
+        let list = "flag contains:6\nshadow 69\nnumber:1337\nflag 1337";
+        let verdict = rules(list).check("69");
+        assert_eq!(verdict.action, Action::Shadow);
+        assert_eq!(verdict.rules, ["flag contains:6", "shadow 69"]);
+        assert_eq!(action(list, "l337"), Action::Reject);
+        assert_eq!(action(list, "6"), Action::Flag);
+        assert_eq!(action(list, "7"), Action::Allow);
+    }
+
+    #[test]
+    fn parses_lists() {
+        let list = "# Banned\n\n69 # nice\nreject   contains:420\n#666\n";
+        let rules = rules(list);
+        assert_eq!(rules.0.len(), 2);
+        assert_eq!(rules.check("69").rules, ["69"]);
+        assert_eq!(rules.check("666").action, Action::Allow);
+
+        let error = Rules::parse("69\nnumber:lots").unwrap_err();
+        assert!(error.starts_with("line 2:"), "{error}");
+        assert!(Rules::parse("regex:(").is_err());
+    }
+
+    #[test]
+    fn rejects_rules_that_match_everything() {
+        for list in [
+            "contains:",
+            "shadow contains:  ",
+            "regex:",
+            "flag regex:.*",
+            "regex:^",
+        ] {
+            assert!(Rules::parse(list).is_err(), "{list}");
+        }
+        assert!(Rules::parse("regex:^6+$").is_ok());
+    }
+}
@@ -0,0 +1,59 @@
+//! Read-modify-write of a Redis key that other instances may be changing at
+//! the same time.
+//!
+//! The key is watched before it's read, and the writes only apply if nobody
+//! changed it in between. Otherwise it's read again and the change worked out
+//! again from the new value.
+
+// Give up after this many conflicting updates from other instances
+const MAX_CONFLICTS: usize = 16;
+
+pub struct Update {
+    key: &'static str,
+    // What was being done, for the error when it never works out
+    what: &'static str,
+    conflicts: usize,
+}
+
+impl Update {
+    pub fn new(key: &'static str, what: &'static str) -> Self {
+        Self {
+            key,
+            what,
+            conflicts: 0,
+        }
+    }
+
+    /// Start watching the key, before reading it. Fails once other instances
+    /// got in the way too many times.
+    pub async fn watch(&self, conn: &mut deadpool_redis::Connection) -> anyhow::Result<()> {
+        if self.conflicts == MAX_CONFLICTS {
+            anyhow::bail!("Too much contention {}", self.what);
+        }
+        redis::cmd("WATCH")
+            .arg(self.key)
+            .query_async::<()>(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Apply the writes unless the key changed since it was watched. Returns
+    /// false if it did, and it needs reading again.
+    pub async fn apply(
+        &mut self,
+        conn: &mut deadpool_redis::Connection,
+        pipe: &mut redis::Pipeline,
+    ) -> anyhow::Result<bool> {
+        let applied: Option<()> = pipe.atomic().query_async(conn).await?;
+        if applied.is_none() {
+            self.conflicts += 1;
+        }
+        Ok(applied.is_some())
+    }
+
+    /// Stop watching the key without changing it
+    pub async fn cancel(&self, conn: &mut deadpool_redis::Connection) -> anyhow::Result<()> {
+        redis::cmd("UNWATCH").query_async::<()>(conn).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Priority classes for waiters, and the weighted-fair order they are served
+//! in.
+//!
+//! Each class has a pass value stored in Redis that advances by its stride
+//! (inversely proportional to its weight) every time one of its waiters is
+//! served, and submissions go to the class with the lowest pass. Higher
+//! classes are served more often, but every class with waiters keeps moving
+//! forward so low priority waiters are never starved. A class that had no
+//! waiters for a while catches up to the current virtual time rather than
+//! getting a burst of service for the time it was idle.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+
+use crate::waiter::Waiter;
+
+const SERVED: &str = "priority_served";
+// Pass of the class that was served most recently
+const VIRTUAL_TIME: &str = "virtual";
+
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub const ALL: [Self; 3] = [Self::Low, Self::Normal, Self::High];
+
+    fn weight(self) -> u64 {
+        match self {
+            Self::Low => 1,
+            Self::Normal => 2,
+            Self::High => 4,
+        }
+    }
+
+    fn stride(self) -> u64 {
+        // Divisible by every weight
+        12 / self.weight()
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            other => Err(format!("Unknown priority: {other}")),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Low => write!(f, "low"),
+            Self::Normal => write!(f, "normal"),
+            Self::High => write!(f, "high"),
+        }
+    }
+}
+
+/// Clients with an API key send it in this header
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// API keys and the highest priority each one may ask for
+#[derive(Clone, Default)]
+pub struct ApiKeys(HashMap<String, Priority>);
+
+impl ApiKeys {
+    /// Parse `key:priority` pairs separated by commas
+    pub fn parse(keys: &str) -> Result<Self, String> {
+        keys.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (key, priority) = pair
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| format!("Expected key:priority, got {pair}"))?;
+                // Or an empty header would do
+                if key.is_empty() {
+                    return Err(format!("Missing key in {pair}"));
+                }
+                Ok((key.to_string(), priority.parse()?))
+            })
+            .collect::<Result<_, String>>()
+            .map(Self)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Priority> {
+        self.0.get(key).copied()
+    }
+}
+
+// Never log the keys themselves
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ApiKeys({} keys)", self.0.len())
+    }
+}
+
+// Pass of each class and the virtual time, as stored in Redis
+#[derive(Debug, Default)]
+struct Passes(HashMap<String, u64>);
+
+impl Passes {
+    fn virtual_time(&self) -> u64 {
+        self.0.get(VIRTUAL_TIME).copied().unwrap_or(0)
+    }
+
+    // A class that was idle is caught up to the virtual time
+    fn pass(&self, priority: Priority) -> u64 {
+        self.0
+            .get(&priority.to_string())
+            .copied()
+            .unwrap_or(0)
+            .max(self.virtual_time())
+    }
+
+    fn order<'a>(&self, waiters: impl DoubleEndedIterator<Item = &'a Waiter>) -> Vec<&'a Waiter> {
+        let mut ordered = waiters.rev().collect::<Vec<_>>();
+        // Stable, so each class stays oldest first. Ties go to the higher class.
+        ordered.sort_by_key(|waiter| {
+            (
+                self.pass(waiter.priority),
+                std::cmp::Reverse(waiter.priority),
+            )
+        });
+        ordered
+    }
+
+    // What to store once a waiter of the class is served
+    fn served(&self, priority: Priority) -> [(String, u64); 2] {
+        let pass = self.pass(priority);
+        [
+            (priority.to_string(), pass + priority.stride()),
+            (VIRTUAL_TIME.to_string(), pass),
+        ]
+    }
+}
+
+/// The order in which to offer a submission to the given waiters, which should
+/// be newest first like the queue. Classes are taken lowest pass first, and
+/// waiters within a class oldest first.
+pub async fn order<'a>(
+    conn: &mut deadpool_redis::Connection,
+    waiters: impl DoubleEndedIterator<Item = &'a Waiter>,
+) -> anyhow::Result<Vec<&'a Waiter>> {
+    let passes = Passes(conn.hgetall(SERVED).await?);
+    Ok(passes.order(waiters))
+}
+
+/// Record that a waiter of the given class was served
+pub async fn served(
+    conn: &mut deadpool_redis::Connection,
+    priority: Priority,
+) -> anyhow::Result<()> {
+    // Racing submissions on other instances may both advance from the same pass,
+    // which only makes the order slightly less fair
+    let passes = Passes(conn.hgetall(SERVED).await?);
+    conn.hset_multiple::<_, _, _, ()>(SERVED, &passes.served(priority))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request_type::RequestType, waiter::Constraint};
+
+    #[test]
+    fn parses_api_keys() {
+        let keys = ApiKeys::parse(" alpha:high,beta:low,, gamma:normal ").unwrap();
+        assert_eq!(keys.get("alpha"), Some(Priority::High));
+        assert_eq!(keys.get("beta"), Some(Priority::Low));
+        assert_eq!(keys.get("gamma"), Some(Priority::Normal));
+        assert_eq!(keys.get("delta"), None);
+        assert_eq!(ApiKeys::parse("").unwrap().get(""), None);
+    }
+
+    #[test]
+    fn rejects_invalid_api_keys() {
+        for keys in ["alpha", "alpha:urgent", ":high", "alpha:high,beta"] {
+            assert!(ApiKeys::parse(keys).is_err(), "{keys}");
+        }
+    }
+
+    fn waiter(priority: Priority) -> Waiter {
+        Waiter {
+            id: uuid::Uuid::now_v7(),
+            note: None,
+            nickname: None,
+            request_type: RequestType::default(),
+            constraint: Constraint::default(),
+            count: 1,
+            created_at: 0,
+            ticket: false,
+            priority,
+            received: 0,
+        }
+    }
+
+    // Serve the first waiter in order, as a submission would
+    fn serve(passes: &mut Passes, waiters: &[Waiter]) -> Priority {
+        let priority = passes.order(waiters.iter())[0].priority;
+        passes.0.extend(passes.served(priority));
+        priority
+    }
+
+    #[test]
+    fn serves_higher_classes_first() {
+        // Newest first, so the low priority waiter has been waiting longest
+        let waiters = [
+            waiter(Priority::High),
+            waiter(Priority::Normal),
+            waiter(Priority::Low),
+        ];
+        let order = Passes::default()
+            .order(waiters.iter())
+            .iter()
+            .map(|waiter| waiter.priority)
+            .collect::<Vec<_>>();
+        assert_eq!(order, [Priority::High, Priority::Normal, Priority::Low]);
+    }
+
+    #[test]
+    fn serves_each_class_by_its_weight() {
+        let waiters = [
+            waiter(Priority::High),
+            waiter(Priority::Normal),
+            waiter(Priority::Low),
+        ];
+        let mut passes = Passes::default();
+        let mut served = HashMap::new();
+        for _ in 0..70 {
+            *served.entry(serve(&mut passes, &waiters)).or_insert(0) += 1;
+        }
+        assert_eq!(served[&Priority::High], 40);
+        assert_eq!(served[&Priority::Normal], 20);
+        assert_eq!(served[&Priority::Low], 10);
+    }
+
+    #[test]
+    fn never_starves_lower_classes() {
+        let waiters = [waiter(Priority::High), waiter(Priority::Low)];
+        let mut passes = Passes::default();
+        // A low priority waiter gets a turn at least once per stride of it
+        let turns = (0..Priority::Low.stride())
+            .map(|_| serve(&mut passes, &waiters))
+            .filter(|priority| *priority == Priority::Low)
+            .count();
+        assert!(turns >= 1);
+    }
+
+    #[test]
+    fn catches_idle_classes_up_to_virtual_time() {
+        let mut passes = Passes::default();
+        let busy = [waiter(Priority::High)];
+        for _ in 0..30 {
+            serve(&mut passes, &busy);
+        }
+        assert!(passes.virtual_time() > 0);
+        assert_eq!(passes.pass(Priority::Low), passes.virtual_time());
+
+        // Rather than being owed every turn it missed while idle
+        let waiters = [waiter(Priority::High), waiter(Priority::Low)];
+        let served = (0..5)
+            .map(|_| serve(&mut passes, &waiters))
+            .collect::<Vec<_>>();
+        assert!(served.contains(&Priority::High));
+        assert_eq!(
+            served
+                .iter()
+                .filter(|priority| **priority == Priority::Low)
+                .count(),
+            1
+        );
+    }
+}
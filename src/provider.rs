@@ -0,0 +1,324 @@
+//! Optional public handles for providers, so they can be credited for the
+//! numbers they deliver.
+//!
+//! A provider claims a handle for their session, and both are kept in signed
+//! cookies so they can't be forged. Handles are unique, a claim in Redis
+//! records which session owns each one.
+
+use std::fmt;
+
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    Json,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, Key, SameSite},
+    SignedCookieJar,
+};
+use redis::AsyncCommands as _;
+use rinja::Template;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    error::RrgError,
+    fallback::PRNG_PROVIDER,
+    moderation::{self, Action},
+    state::AppState,
+};
+
+const SESSION_COOKIE: &str = "rrg_session";
+const HANDLE_COOKIE: &str = "rrg_handle";
+
+// Numbers delivered by each handle, by its normalised form
+const DELIVERIES: &str = "provider_deliveries";
+// How long recent deliveries of each handle took in milliseconds, newest first
+const RESPONSE_SAMPLES: isize = 100;
+
+const MIN_HANDLE_LENGTH: usize = 3;
+const MAX_HANDLE_LENGTH: usize = 32;
+
+/// Key for signing session cookies
+#[derive(Clone)]
+pub struct CookieKey(Key);
+
+impl CookieKey {
+    /// Parse a hex encoded key of at least 64 bytes
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let key = hex::decode(key.trim()).map_err(|e| e.to_string())?;
+        Key::try_from(key.as_slice())
+            .map(Self)
+            .map_err(|_| "Expected at least 64 bytes".to_string())
+    }
+
+    pub fn generate() -> Self {
+        Self(Key::generate())
+    }
+}
+
+// Never log the key
+impl fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CookieKey")
+    }
+}
+
+impl FromRef<AppState> for Key {
+    fn from_ref(state: &AppState) -> Self {
+        state.cookie_key.0.clone()
+    }
+}
+
+// Handles differing only by case belong to the same provider
+fn normalise(handle: &str) -> String {
+    handle.to_lowercase()
+}
+
+fn claim_key(handle: &str) -> String {
+    format!("provider_handle:{}", normalise(handle))
+}
+
+fn responses_key(handle: &str) -> String {
+    format!("provider_responses:{}", normalise(handle))
+}
+
+fn session_id(jar: &SignedCookieJar) -> Option<Uuid> {
+    jar.get(SESSION_COOKIE)
+        .and_then(|cookie| cookie.value().parse().ok())
+}
+
+/// The handle the session says it has, without checking it still owns it
+pub fn claimed_handle(jar: &SignedCookieJar) -> Option<String> {
+    jar.get(HANDLE_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// The handle of the provider making the request, if they still own one
+pub async fn handle(
+    conn: &mut deadpool_redis::Connection,
+    jar: &SignedCookieJar,
+) -> anyhow::Result<Option<String>> {
+    let (Some(session), Some(handle)) = (session_id(jar), claimed_handle(jar)) else {
+        return Ok(None);
+    };
+    let owner: Option<Uuid> = conn.get(claim_key(&handle)).await?;
+    Ok((owner == Some(session)).then_some(handle))
+}
+
+/// Record that a handle delivered a number, and how long after the request
+/// was made
+pub async fn delivered(
+    conn: &mut deadpool_redis::Connection,
+    handle: &str,
+    response_ms: u64,
+) -> anyhow::Result<()> {
+    let responses = responses_key(handle);
+    redis::pipe()
+        .zincr(DELIVERIES, normalise(handle), 1)
+        .ignore()
+        .lpush(&responses, response_ms)
+        .ignore()
+        .ltrim(&responses, 0, RESPONSE_SAMPLES - 1)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct Standing {
+    pub handle: String,
+    pub delivered: u64,
+    // Over their most recent deliveries
+    pub median_response_ms: Option<u64>,
+}
+
+/// The `size` handles that delivered the most numbers
+pub async fn leaderboard(
+    conn: &mut deadpool_redis::Connection,
+    size: usize,
+) -> anyhow::Result<Vec<Standing>> {
+    let top: Vec<(String, u64)> = conn
+        .zrevrange_withscores(DELIVERIES, 0, isize::try_from(size)? - 1)
+        .await?;
+
+    let mut pipe = redis::pipe();
+    for (handle, _) in &top {
+        pipe.lrange(responses_key(handle), 0, -1);
+    }
+    let responses: Vec<Vec<u64>> = pipe.query_async(conn).await?;
+
+    Ok(top
+        .into_iter()
+        .zip(responses)
+        .map(|((handle, delivered), mut responses)| {
+            responses.sort_unstable();
+            Standing {
+                handle,
+                delivered,
+                median_response_ms: responses.get(responses.len() / 2).copied(),
+            }
+        })
+        .collect())
+}
+
+fn validate(handle: &str) -> Result<(), &'static str> {
+    let length = handle.chars().count();
+    if !(MIN_HANDLE_LENGTH..=MAX_HANDLE_LENGTH).contains(&length) {
+        return Err("Handles must be 3 to 32 characters long");
+    }
+    if !handle
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("Handles may only use letters, digits, _ and -");
+    }
+    // Mustn't be confused with anonymous providers or the server itself
+    let normalised = normalise(handle);
+    if normalised.starts_with("anon-") || normalised == PRNG_PROVIDER {
+        return Err("That handle is reserved");
+    }
+    let verdict = moderation::check(handle);
+    if verdict.action >= Action::Shadow {
+        tracing::warn!("Rejecting banned handle matching {:?}", verdict.rules);
+        sentry::configure_scope(|scope| scope.set_tag("naughty_user", "true"));
+        return Err("That handle is not allowed");
+    }
+    Ok(())
+}
+
+// Give up a handle if the session still owns it, so whoever claims it next
+// doesn't inherit its deliveries
+async fn release(
+    conn: &mut deadpool_redis::Connection,
+    session: Uuid,
+    handle: &str,
+) -> anyhow::Result<()> {
+    let key = claim_key(handle);
+    if conn.get::<_, Option<Uuid>>(&key).await? == Some(session) {
+        redis::pipe()
+            .atomic()
+            .del(&key)
+            .ignore()
+            .zrem(DELIVERIES, normalise(handle))
+            .ignore()
+            .del(responses_key(handle))
+            .ignore()
+            .query_async::<()>(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+fn cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .permanent()
+        .build()
+}
+
+#[derive(Template)]
+#[template(path = "index.html", block = "handle_form")]
+struct HandleFormTemplate<'a> {
+    handle: Option<String>,
+    handle_context: &'a str,
+}
+
+fn handle_form(
+    status: StatusCode,
+    handle: Option<String>,
+    handle_context: &str,
+) -> Result<(StatusCode, Html<String>), RrgError> {
+    Ok((
+        status,
+        Html(
+            HandleFormTemplate {
+                handle,
+                handle_context,
+            }
+            .render()
+            .map_err(anyhow::Error::from)?,
+        ),
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HandleParams {
+    handle: String,
+}
+
+/// Claim a handle for the session, giving up any it had before
+#[tracing::instrument]
+pub async fn set_handle(
+    jar: SignedCookieJar,
+    State(state): State<AppState>,
+    Json(HandleParams { handle }): Json<HandleParams>,
+) -> Result<impl IntoResponse, RrgError> {
+    let previous = claimed_handle(&jar);
+    let handle = handle.trim().to_string();
+    if let Err(message) = validate(&handle) {
+        return Ok((
+            jar,
+            handle_form(StatusCode::BAD_REQUEST, previous, message)?,
+        ));
+    }
+
+    let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+    let session = session_id(&jar).unwrap_or_else(Uuid::new_v4);
+    let claimed: bool = conn
+        .set_nx(claim_key(&handle), session)
+        .await
+        .map_err(anyhow::Error::from)?;
+    if !claimed
+        && conn
+            .get::<_, Option<Uuid>>(claim_key(&handle))
+            .await
+            .map_err(anyhow::Error::from)?
+            != Some(session)
+    {
+        return Ok((
+            jar,
+            handle_form(
+                StatusCode::CONFLICT,
+                previous,
+                "Someone else already has that handle!",
+            )?,
+        ));
+    }
+    if let Some(previous) = previous.filter(|previous| claim_key(previous) != claim_key(&handle)) {
+        release(&mut conn, session, &previous).await?;
+    }
+
+    let jar = jar
+        .add(cookie(SESSION_COOKIE, session.to_string()))
+        .add(cookie(HANDLE_COOKIE, handle.clone()));
+    Ok((
+        jar,
+        handle_form(
+            StatusCode::OK,
+            Some(handle),
+            "Your numbers will be credited!",
+        )?,
+    ))
+}
+
+/// Give up the session's handle and go back to being anonymous
+#[tracing::instrument]
+pub async fn clear_handle(
+    jar: SignedCookieJar,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RrgError> {
+    if let (Some(session), Some(handle)) = (session_id(&jar), claimed_handle(&jar)) {
+        let mut conn = state.redis.get().await.map_err(anyhow::Error::from)?;
+        release(&mut conn, session, &handle).await?;
+    }
+    let jar = jar.remove(Cookie::build(HANDLE_COOKIE).path("/"));
+    Ok((
+        jar,
+        handle_form(StatusCode::OK, None, "You're anonymous again.")?,
+    ))
+}
@@ -0,0 +1,314 @@
+//! Statistical tests of how random the submitted numbers really are.
+//!
+//! Every submission is appended to a Redis stream in the order it arrived.
+//! The tests only need running sums, which are cached in Redis along with the
+//! last stream entry they include, so each report only has to fold in the
+//! submissions that arrived since the previous one.
+//!
+//! Each test passes when its statistic is within what a truly random source
+//! would produce 95% of the time.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{optimistic::Update, submission::Submission};
+
+// Every submission in the order it arrived
+const LOG: &str = "submission_log";
+// Roughly how many submissions the log keeps. Anything trimmed before a report
+// folds it in is left out of the tests.
+const LOG_LENGTH: usize = 100_000;
+// Running sums for the tests, and the last log entry they include
+const ACCUMULATORS: &str = "quality_accumulators";
+
+// Log entries to fold in per round trip
+const BATCH_SIZE: usize = 1000;
+
+// Fewer samples than this and the tests don't mean much
+const MIN_SAMPLES: u64 = 50;
+
+// Chi-square critical values at the 5% level
+const CHI_SQUARE_9_DF: f64 = 16.919;
+const CHI_SQUARE_8_DF: f64 = 15.507;
+// Two-sided standard normal critical value at the 5% level
+const Z_CRITICAL: f64 = 1.96;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct Accumulators {
+    // Stream ID of the last submission folded in
+    last_id: Option<String>,
+    submissions: u64,
+    // How often each digit appears anywhere in a submission
+    digits: [u64; 10],
+    // How often each digit leads a non-zero number, index 0 is unused
+    leading: [u64; 10],
+    // Submissions that are numbers, for the numeric tests
+    numbers: u64,
+    sum: f64,
+    sum_squares: f64,
+    // Of each number with the one after it
+    sum_products: f64,
+    first: Option<f64>,
+    last: Option<f64>,
+    // Runs up and down, ignoring repeated numbers
+    runs: u64,
+    // -1 while going down, 1 while going up
+    direction: i8,
+}
+
+impl Accumulators {
+    fn fold(&mut self, value: &str) {
+        self.submissions += 1;
+        for digit in value.chars().filter_map(|c| c.to_digit(10)) {
+            self.digits[digit as usize] += 1;
+        }
+
+        let Some(number) = value.trim().parse::<f64>().ok().filter(|n| n.is_finite()) else {
+            return;
+        };
+        // Numbers so big the sums would overflow are left out of the numeric
+        // tests, infinite sums can't be stored or tested against
+        let product = self.last.map_or(0.0, |last| last * number);
+        if ![
+            self.sum + number,
+            self.sum_squares + number * number,
+            self.sum_products + product,
+        ]
+        .iter()
+        .all(|sum| sum.is_finite())
+        {
+            return;
+        }
+        if let Some(leading) = value
+            .chars()
+            .filter_map(|c| c.to_digit(10))
+            .find(|&digit| digit != 0)
+        {
+            self.leading[leading as usize] += 1;
+        }
+
+        self.numbers += 1;
+        self.sum += number;
+        self.sum_squares += number * number;
+        self.first.get_or_insert(number);
+        if let Some(last) = self.last {
+            self.sum_products += product;
+            let direction = if number > last {
+                1
+            } else if number < last {
+                -1
+            } else {
+                0
+            };
+            if direction != 0 && direction != self.direction {
+                self.runs += 1;
+                self.direction = direction;
+            }
+        }
+        self.last = Some(number);
+    }
+}
+
+/// The outcome of a single test
+#[derive(Serialize, Debug)]
+pub struct Outcome {
+    pub name: &'static str,
+    pub samples: u64,
+    pub statistic: f64,
+    // Largest statistic a random source would usually produce
+    pub critical_value: f64,
+    // None until there are enough samples to tell
+    pub passed: Option<bool>,
+}
+
+impl Outcome {
+    fn new(name: &'static str, samples: u64, statistic: f64, critical_value: f64) -> Self {
+        let passed = (samples >= MIN_SAMPLES && statistic.is_finite())
+            .then_some(statistic <= critical_value);
+        Self {
+            name,
+            samples,
+            statistic,
+            critical_value,
+            passed,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub submissions: u64,
+    pub numbers: u64,
+    pub tests: Vec<Outcome>,
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn chi_square(observed: &[u64], expected: impl Fn(usize) -> f64) -> f64 {
+    observed
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| {
+            let expected = expected(i);
+            (count as f64 - expected).powi(2) / expected
+        })
+        .sum()
+}
+
+#[allow(clippy::cast_precision_loss)]
+impl From<&Accumulators> for Report {
+    fn from(acc: &Accumulators) -> Self {
+        // Every digit equally often
+        let digit_total = acc.digits.iter().sum::<u64>();
+        let digits = Outcome::new(
+            "Digit frequencies (chi-square)",
+            digit_total,
+            chi_square(&acc.digits, |_| digit_total as f64 / 10.0),
+            CHI_SQUARE_9_DF,
+        );
+
+        // Leading digits should follow Benford's law
+        let leading_total = acc.leading.iter().sum::<u64>();
+        let benford = Outcome::new(
+            "Benford's law deviation (chi-square)",
+            leading_total,
+            chi_square(&acc.leading[1..], |i| {
+                leading_total as f64 * (1.0 + 1.0 / (i + 1) as f64).log10()
+            }),
+            CHI_SQUARE_8_DF,
+        );
+
+        // Number of runs up and down compared to what's expected of n numbers
+        let n = acc.numbers as f64;
+        let runs_mean = (2.0 * n - 1.0) / 3.0;
+        let runs_variance = (16.0 * n - 29.0) / 90.0;
+        let runs = Outcome::new(
+            "Runs up and down (|z|)",
+            acc.numbers,
+            ((acc.runs as f64 - runs_mean) / runs_variance.sqrt()).abs(),
+            Z_CRITICAL,
+        );
+
+        // Knuth's serial correlation coefficient, wrapping around from the last
+        // number to the first
+        let wrap = acc
+            .first
+            .zip(acc.last)
+            .map_or(0.0, |(first, last)| first * last);
+        let correlation = (n * (acc.sum_products + wrap) - acc.sum * acc.sum)
+            / (n * acc.sum_squares - acc.sum * acc.sum);
+        let correlation_mean = -1.0 / (n - 1.0);
+        let correlation_deviation = (n * n / ((n - 1.0).powi(2) * (n - 2.0))).sqrt();
+        let serial = Outcome::new(
+            "Serial correlation (|z|)",
+            acc.numbers,
+            ((correlation - correlation_mean) / correlation_deviation).abs(),
+            Z_CRITICAL,
+        );
+
+        Self {
+            submissions: acc.submissions,
+            numbers: acc.numbers,
+            tests: vec![digits, benford, runs, serial],
+        }
+    }
+}
+
+/// Append a submission to the log the report is built from
+pub async fn record(
+    conn: &mut deadpool_redis::Connection,
+    submission: &Submission,
+) -> anyhow::Result<()> {
+    redis::cmd("XADD")
+        .arg(LOG)
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(LOG_LENGTH)
+        .arg("*")
+        .arg("value")
+        .arg(&submission.value)
+        .query_async::<String>(conn)
+        .await?;
+    Ok(())
+}
+
+/// Bring the cached sums up to date with the log, and report on them
+pub async fn report(conn: &mut deadpool_redis::Connection) -> anyhow::Result<Report> {
+    let mut update = Update::new(ACCUMULATORS, "updating the quality report");
+    loop {
+        update.watch(conn).await?;
+        let cached: Option<String> = redis::cmd("GET")
+            .arg(ACCUMULATORS)
+            .query_async(conn)
+            .await?;
+        let mut acc: Accumulators = cached
+            .map(|cached| serde_json::from_str(&cached))
+            .transpose()?
+            .unwrap_or_default();
+
+        // Exclusive of the last entry already folded in
+        let start = acc
+            .last_id
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |id| format!("({id}"));
+        let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+            .arg(LOG)
+            .arg(start)
+            .arg("+")
+            .arg("COUNT")
+            .arg(BATCH_SIZE)
+            .query_async(conn)
+            .await?;
+        if entries.is_empty() {
+            update.cancel(conn).await?;
+            return Ok(Report::from(&acc));
+        }
+
+        for (id, fields) in &entries {
+            if let [_, value] = fields.as_slice() {
+                acc.fold(value);
+            }
+            acc.last_id = Some(id.clone());
+        }
+
+        // Another instance may have folded in the same entries meanwhile, then
+        // this batch is read again. Either way the next batch follows.
+        update
+            .apply(
+                conn,
+                redis::pipe().set(ACCUMULATORS, serde_json::to_string(&acc)?),
+            )
+            .await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_numbers() {
+        let mut acc = Accumulators::default();
+        for value in ["3", "1", "2"] {
+            acc.fold(value);
+        }
+        assert_eq!(acc.numbers, 3);
+        assert!((acc.sum - 6.0).abs() < f64::EPSILON);
+        assert!((acc.sum_squares - 14.0).abs() < f64::EPSILON);
+        assert!((acc.sum_products - 5.0).abs() < f64::EPSILON);
+        assert_eq!(acc.runs, 2);
+    }
+
+    #[test]
+    fn skips_numbers_that_would_overflow() {
+        let mut acc = Accumulators::default();
+        for value in ["1e200", "5", "1e200", "1e300"] {
+            acc.fold(value);
+        }
+        assert_eq!(acc.submissions, 4);
+        assert_eq!(acc.numbers, 1);
+        assert!(acc.sum_squares.is_finite() && acc.sum_products.is_finite());
+        assert_eq!(acc.last, Some(5.0));
+        assert!(
+            serde_json::from_str::<Accumulators>(&serde_json::to_string(&acc).unwrap()).is_ok()
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Token bucket rate limits per route and per client, kept in Redis so they
+//! hold across every instance.
+//!
+//! Clients with a known API key are limited by key, everyone else by address.
+//! Each bucket holds up to `count` requests and refills completely over
+//! `period`. Routes are named by their first path segment under `/api`, except
+//! that checking on a request is `poll` so that waiting for a number doesn't
+//! use up the budget for making requests.
+
+use std::{collections::HashMap, net::IpAddr, sync::LazyLock, time::Duration};
+
+use axum::http::Method;
+use sha2::{Digest as _, Sha256};
+
+// Limits for routes that aren't configured otherwise. Admin requests are kept
+// slow so the admin token can't be guessed, handles so nobody can claim them
+// all, and bytes so nobody can drain the entropy pool.
+const DEFAULT_LIMITS: &str = "submit=30/60,get=60/60,requests=60/60,poll=120/60,admin=5/60,\
+                              handle=10/60,bytes=30/60,verify=60/60,stats=60/60,beacon=120/60,\
+                              get:key=600/60,requests:key=600/60,poll:key=1200/60";
+
+// Take a token from the bucket if there is one. Returns 0 if one was taken, or
+// how many milliseconds until there will be one. Uses the Redis clock so every
+// instance agrees on how much has refilled.
+static TAKE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local capacity = tonumber(ARGV[1])
+        local period = tonumber(ARGV[2])
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+        local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+        local tokens = tonumber(bucket[1]) or capacity
+        local updated = tonumber(bucket[2]) or now
+        tokens = math.min(capacity, tokens + (now - updated) * capacity / period)
+
+        local wait = 0
+        if tokens >= 1 then
+            tokens = tokens - 1
+        else
+            wait = math.ceil((1 - tokens) * period / capacity)
+        end
+        redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+        redis.call('PEXPIRE', KEYS[1], period)
+        return wait
+        ",
+    )
+});
+
+/// The route a request to the API is limited as
+pub fn route(method: &Method, path: &str) -> String {
+    let mut segments = path
+        .trim_start_matches("/api")
+        .split('/')
+        .filter(|segment| !segment.is_empty());
+    match (segments.next(), segments.next()) {
+        (Some("requests"), Some(_)) if method == Method::GET => "poll".to_string(),
+        (route, _) => route.unwrap_or_default().to_string(),
+    }
+}
+
+/// Who a request is limited as
+pub enum Identity {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+impl Identity {
+    // Never store the API key itself
+    fn bucket(&self, route: &str) -> String {
+        match self {
+            Self::Ip(ip) => format!("rate_limit:{route}:ip:{ip}"),
+            Self::ApiKey(key) => {
+                format!(
+                    "rate_limit:{route}:key:{}",
+                    hex::encode(Sha256::digest(key))
+                )
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    count: u32,
+    period: Duration,
+}
+
+impl Limit {
+    fn parse(limit: &str) -> Result<Self, String> {
+        let (count, seconds) = limit
+            .split_once('/')
+            .ok_or_else(|| format!("Expected count/seconds, got {limit}"))?;
+        let count = count
+            .parse()
+            .map_err(|_| format!("Invalid request count: {count}"))?;
+        let seconds = seconds
+            .parse()
+            .map_err(|_| format!("Invalid period: {seconds}"))?;
+        if count == 0 || seconds == 0 {
+            return Err(format!("Limits must be positive, got {limit}"));
+        }
+        Ok(Self {
+            count,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+/// Limits by route, and whether they apply to API keys or addresses
+#[derive(Clone, Debug)]
+pub struct RateLimits(HashMap<(String, bool), Limit>);
+
+impl RateLimits {
+    /// Parse `route=count/seconds` pairs separated by commas, where a route
+    /// of `route:key` applies to API keys. Routes not mentioned keep their
+    /// default limits.
+    pub fn parse(limits: &str) -> Result<Self, String> {
+        let mut parsed = Self(HashMap::new());
+        for pair in DEFAULT_LIMITS.split(',').chain(limits.split(',')) {
+            if pair.trim().is_empty() {
+                continue;
+            }
+            let (route, limit) = pair
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Expected route=count/seconds, got {pair}"))?;
+            let (route, key) = match route.strip_suffix(":key") {
+                Some(route) => (route, true),
+                None => (route, false),
+            };
+            parsed
+                .0
+                .insert((route.to_string(), key), Limit::parse(limit)?);
+        }
+        Ok(parsed)
+    }
+
+    // Keys without a limit of their own share the limit by address
+    fn get(&self, route: &str, identity: &Identity) -> Option<Limit> {
+        let key = matches!(identity, Identity::ApiKey(_));
+        self.0
+            .get(&(route.to_string(), key))
+            .or_else(|| self.0.get(&(route.to_string(), false)))
+            .copied()
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::parse("").unwrap()
+    }
+}
+
+/// Take one request from the identity's bucket for the route. Returns how long
+/// to wait if the bucket is empty.
+pub async fn take(
+    conn: &mut deadpool_redis::Connection,
+    limits: &RateLimits,
+    route: &str,
+    identity: &Identity,
+) -> anyhow::Result<Option<Duration>> {
+    let Some(limit) = limits.get(route, identity) else {
+        return Ok(None);
+    };
+    let wait: u64 = TAKE_SCRIPT
+        .key(identity.bucket(route))
+        .arg(limit.count)
+        .arg(u64::try_from(limit.period.as_millis())?)
+        .invoke_async(conn)
+        .await?;
+    Ok((wait > 0).then(|| Duration::from_millis(wait)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limits: &RateLimits, route: &str, identity: &Identity) -> Option<(u32, u64)> {
+        limits
+            .get(route, identity)
+            .map(|limit| (limit.count, limit.period.as_secs()))
+    }
+
+    #[test]
+    fn names_routes() {
+        assert_eq!(route(&Method::POST, "/api/submit"), "submit");
+        assert_eq!(route(&Method::GET, "/api/get/3"), "get");
+        assert_eq!(route(&Method::POST, "/api/requests"), "requests");
+        assert_eq!(route(&Method::GET, "/api/requests/abc"), "poll");
+        assert_eq!(route(&Method::DELETE, "/api/requests/abc"), "requests");
+        assert_eq!(route(&Method::POST, "/api/handle"), "handle");
+        assert_eq!(route(&Method::GET, "/api/bytes"), "bytes");
+        assert_eq!(route(&Method::GET, "/api/stats/quality"), "stats");
+        assert_eq!(route(&Method::GET, "/api"), "");
+    }
+
+    #[test]
+    fn parses_limits() {
+        let ip = Identity::Ip(IpAddr::from([127, 0, 0, 1]));
+        let key = Identity::ApiKey("secret".to_string());
+        let limits = RateLimits::parse(" submit=5/10, poll:key=1/1 ,stats=2/3").unwrap();
+        assert_eq!(limit(&limits, "submit", &ip), Some((5, 10)));
+        // Keys without a limit of their own share the one by address
+        assert_eq!(limit(&limits, "submit", &key), Some((5, 10)));
+        assert_eq!(limit(&limits, "poll", &ip), Some((120, 60)));
+        assert_eq!(limit(&limits, "poll", &key), Some((1, 1)));
+        assert_eq!(limit(&limits, "stats", &ip), Some((2, 3)));
+        assert_eq!(limit(&limits, "admin", &ip), Some((5, 60)));
+        assert_eq!(limit(&limits, "admin", &key), Some((5, 60)));
+        assert_eq!(limit(&limits, "other", &ip), None);
+        assert_eq!(limit(&RateLimits::default(), "get", &key), Some((600, 60)));
+    }
+
+    #[test]
+    fn limits_every_route_by_default() {
+        let ip = Identity::Ip(IpAddr::from([127, 0, 0, 1]));
+        let limits = RateLimits::default();
+        for route in [
+            "submit", "get", "requests", "poll", "verify", "handle", "bytes", "beacon", "stats",
+            "admin",
+        ] {
+            assert!(limit(&limits, route, &ip).is_some(), "{route}");
+        }
+        assert_eq!(limit(&limits, "handle", &ip), Some((10, 60)));
+        assert_eq!(limit(&limits, "bytes", &ip), Some((30, 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        for limits in [
+            "submit",
+            "submit=5",
+            "submit=x/10",
+            "submit=5/x",
+            "submit=0/10",
+            "submit=5/0",
+        ] {
+            assert!(RateLimits::parse(limits).is_err(), "{limits}");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Signed receipts that let anyone check a delivery really came from us.
+//!
+//! A receipt is signed with the server's Ed25519 key over its JSON encoding,
+//! with fields in the order they are declared here. The public key is served
+//! at `/.well-known/rrg-signing-key`, and `/api/verify` checks a receipt.
+
+use std::fmt;
+
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use uuid::Uuid;
+
+use crate::fallback::FallbackPolicy;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Receipt {
+    pub request_id: Uuid,
+    pub numbers: Vec<String>,
+    // Unix time in milliseconds
+    pub fulfilled_at_ms: u64,
+    // Hex SHA-256 of the provider handles, one per line, in order of first contribution
+    pub providers_hash: String,
+    // Left out entirely when every number came from people
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<FallbackPolicy>,
+}
+
+impl Receipt {
+    pub fn new(
+        request_id: Uuid,
+        numbers: Vec<String>,
+        fulfilled_at_ms: u64,
+        providers: &[String],
+        fallback: Option<FallbackPolicy>,
+    ) -> Self {
+        Self {
+            request_id,
+            numbers,
+            fulfilled_at_ms,
+            providers_hash: hex::encode(Sha256::digest(providers.join("\n"))),
+            fallback,
+        }
+    }
+
+    fn message(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Receipts are always valid JSON")
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedReceipt {
+    pub receipt: Receipt,
+    // Hex Ed25519 signature
+    pub signature: String,
+}
+
+#[derive(Clone)]
+pub struct Signer(SigningKey);
+
+impl Signer {
+    /// Parse a hex encoded 32 byte Ed25519 seed
+    pub fn from_hex(seed: &str) -> Result<Self, String> {
+        let seed: [u8; 32] = hex::decode(seed.trim())
+            .map_err(|e| e.to_string())?
+            .try_into()
+            .map_err(|_| "Expected 32 bytes".to_string())?;
+        Ok(Self(SigningKey::from_bytes(&seed)))
+    }
+
+    pub fn generate() -> Self {
+        Self(SigningKey::from_bytes(&rand::random()))
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.0.verifying_key().as_bytes())
+    }
+
+    pub fn sign(&self, receipt: Receipt) -> SignedReceipt {
+        let signature = hex::encode(self.sign_bytes(&receipt.message()));
+        SignedReceipt { receipt, signature }
+    }
+
+    pub fn sign_bytes(&self, message: &[u8]) -> [u8; 64] {
+        self.0.sign(message).to_bytes()
+    }
+
+    /// Whether the receipt was signed by this key
+    pub fn verify(&self, signed: &SignedReceipt) -> bool {
+        let Ok(signature) = hex::decode(&signed.signature) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&signature) else {
+            return false;
+        };
+        self.0
+            .verifying_key()
+            .verify(&signed.receipt.message(), &signature)
+            .is_ok()
+    }
+}
+
+// Never log the private key
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signer({})", self.public_key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(fallback: Option<FallbackPolicy>) -> Receipt {
+        Receipt::new(
+            Uuid::nil(),
+            vec!["4".to_string()],
+            1_700_000_000_000,
+            &["anon-1".to_string()],
+            fallback,
+        )
+    }
+
+    #[test]
+    fn verifies_its_own_receipts() {
+        let signer = Signer::generate();
+        let signed = signer.sign(receipt(Some(FallbackPolicy::Prng)));
+        assert!(signer.verify(&signed));
+        assert!(!Signer::generate().verify(&signed));
+    }
+
+    #[test]
+    fn signs_the_fallback() {
+        let signer = Signer::generate();
+        let mut signed = signer.sign(receipt(Some(FallbackPolicy::Prng)));
+        signed.receipt.fallback = None;
+        assert!(!signer.verify(&signed));
+    }
+
+    #[test]
+    fn leaves_out_a_missing_fallback() {
+        let message = String::from_utf8(receipt(None).message()).unwrap();
+        assert!(!message.contains("fallback"));
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::RrgError, waiter::Constraint};
+
+const MAX_PRECISION: u32 = 6;
+const MAX_HEX_BYTES: usize = 64;
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: i64 = 1000;
+const MAX_MODIFIER: i64 = 1_000_000;
+
+/// The kind of value a client wants back.
+///
+/// Typed requests are filled from one or more crowd submissions, each of
+/// which must fall within a small range. Every mapping from those draws to
+/// the final value is a bijection (or a plain sum, for dice), so a provider
+/// choosing uniformly within the range gives a uniformly distributed result.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RequestType {
+    /// Whatever a human typed
+    #[default]
+    Any,
+    Int,
+    Float {
+        precision: u32,
+    },
+    Coin,
+    Dice {
+        count: u32,
+        sides: i64,
+        modifier: i64,
+    },
+    Hex {
+        bytes: usize,
+    },
+    Uuid,
+}
+
+impl RequestType {
+    pub fn from_params(
+        kind: Option<&str>,
+        precision: Option<u32>,
+        bytes: Option<usize>,
+    ) -> Result<Self, RrgError> {
+        let request_type = match kind {
+            None | Some("any") => Self::Any,
+            Some("int") => Self::Int,
+            Some("float") => {
+                let precision = precision.unwrap_or(2);
+                if !(1..=MAX_PRECISION).contains(&precision) {
+                    return Err(RrgError::BadRequest(format!(
+                        "precision must be between 1 and {MAX_PRECISION}"
+                    )));
+                }
+                Self::Float { precision }
+            }
+            Some("coin") => Self::Coin,
+            Some("hex") => {
+                let bytes = bytes.unwrap_or(16);
+                if !(1..=MAX_HEX_BYTES).contains(&bytes) {
+                    return Err(RrgError::BadRequest(format!(
+                        "bytes must be between 1 and {MAX_HEX_BYTES}"
+                    )));
+                }
+                Self::Hex { bytes }
+            }
+            Some("uuid") => Self::Uuid,
+            Some(notation) => Self::parse_dice(notation)
+                .ok_or_else(|| RrgError::BadRequest(format!("Unknown request type: {notation}")))?,
+        };
+
+        if precision.is_some() && !matches!(request_type, Self::Float { .. }) {
+            return Err(RrgError::BadRequest(
+                "precision only applies to float requests".to_string(),
+            ));
+        }
+        if bytes.is_some() && !matches!(request_type, Self::Hex { .. }) {
+            return Err(RrgError::BadRequest(
+                "bytes only applies to hex requests".to_string(),
+            ));
+        }
+
+        Ok(request_type)
+    }
+
+    // Dice notation such as "d20", "3d6" or "2d10-1". An unescaped + in a
+    // query string arrives as a space, so "3d6 2" is read as "3d6+2".
+    fn parse_dice(notation: &str) -> Option<Self> {
+        let notation = notation.replace(' ', "+");
+        let (count, rest) = notation.split_once('d')?;
+        let count = if count.is_empty() {
+            1
+        } else {
+            count.parse().ok()?
+        };
+
+        let (sides, modifier) = match rest.find(['+', '-']) {
+            Some(index) => (&rest[..index], rest[index..].parse().ok()?),
+            None => (rest, 0),
+        };
+        let sides = sides.parse().ok()?;
+
+        ((1..=MAX_DICE).contains(&count)
+            && (2..=MAX_SIDES).contains(&sides)
+            && (-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier))
+        .then_some(Self::Dice {
+            count,
+            sides,
+            modifier,
+        })
+    }
+
+    /// How many crowd submissions it takes to produce one value
+    pub fn draws(&self) -> usize {
+        match self {
+            Self::Any | Self::Int | Self::Float { .. } | Self::Coin => 1,
+            Self::Dice { count, .. } => *count as usize,
+            Self::Hex { bytes } => *bytes,
+            Self::Uuid => 16,
+        }
+    }
+
+    /// The constraint every submission for this type has to satisfy, given
+    /// the constraint the client asked for
+    pub fn draw_constraint(&self, requested: Constraint) -> Result<Constraint, RrgError> {
+        let constraint = match self {
+            Self::Any => return Ok(requested),
+            Self::Int => {
+                return Ok(Constraint {
+                    integer: true,
+                    ..requested
+                })
+            }
+            Self::Float { precision } => Constraint::range(0, 10_i64.pow(*precision) - 1),
+            Self::Coin => Constraint::range(0, 1),
+            Self::Dice { sides, .. } => Constraint::range(1, *sides),
+            Self::Hex { .. } | Self::Uuid => Constraint::range(0, 255),
+        };
+
+        if !requested.is_unconstrained() {
+            return Err(RrgError::BadRequest(
+                "min, max and not only apply to int requests".to_string(),
+            ));
+        }
+
+        Ok(constraint)
+    }
+
+    /// Turn the submissions collected for this type into the final value.
+    /// Every submission has already been checked against `draw_constraint`.
+    pub fn finish(&self, submissions: &[String]) -> String {
+        let draws = || {
+            submissions
+                .iter()
+                .map(|submission| submission.trim().parse::<i64>().unwrap())
+        };
+
+        match self {
+            Self::Any => submissions[0].clone(),
+            Self::Int => draws().next().unwrap().to_string(),
+            Self::Float { precision } => format!(
+                "0.{:0width$}",
+                draws().next().unwrap(),
+                width = *precision as usize
+            ),
+            Self::Coin => match draws().next() {
+                Some(0) => "heads".to_string(),
+                _ => "tails".to_string(),
+            },
+            Self::Dice { modifier, .. } => (draws().sum::<i64>() + modifier).to_string(),
+            Self::Hex { .. } => draws().map(|byte| format!("{byte:02x}")).collect(),
+            Self::Uuid => {
+                let mut bytes = [0; 16];
+                for (byte, draw) in bytes.iter_mut().zip(draws()) {
+                    *byte = u8::try_from(draw).unwrap();
+                }
+                // Overwrites the version and variant bits, the rest stay uniform
+                uuid::Builder::from_random_bytes(bytes)
+                    .into_uuid()
+                    .to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for RequestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "anything"),
+            Self::Int => write!(f, "integer"),
+            Self::Float { precision } => write!(f, "float, {precision} digits"),
+            Self::Coin => write!(f, "coin flip"),
+            Self::Dice {
+                count,
+                sides,
+                modifier,
+            } => match modifier {
+                0 => write!(f, "{count}d{sides}"),
+                m => write!(f, "{count}d{sides}{m:+}"),
+            },
+            Self::Hex { bytes } => write!(f, "{bytes} hex bytes"),
+            Self::Uuid => write!(f, "uuid"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dice() {
+        assert_eq!(
+            RequestType::parse_dice("d20"),
+            Some(RequestType::Dice {
+                count: 1,
+                sides: 20,
+                modifier: 0
+            })
+        );
+        assert_eq!(
+            RequestType::parse_dice("2d10-1"),
+            Some(RequestType::Dice {
+                count: 2,
+                sides: 10,
+                modifier: -1
+            })
+        );
+    }
+
+    #[test]
+    fn reads_a_space_as_plus() {
+        let dice = Some(RequestType::Dice {
+            count: 3,
+            sides: 6,
+            modifier: 2,
+        });
+        assert_eq!(RequestType::parse_dice("3d6+2"), dice);
+        assert_eq!(RequestType::parse_dice("3d6 2"), dice);
+    }
+
+    #[test]
+    fn bounds_dice() {
+        assert_eq!(RequestType::parse_dice("0d6"), None);
+        assert_eq!(RequestType::parse_dice("101d6"), None);
+        assert_eq!(RequestType::parse_dice("1d1"), None);
+        assert_eq!(RequestType::parse_dice("1d1001"), None);
+        assert_eq!(RequestType::parse_dice("1d6+1000001"), None);
+        assert_eq!(RequestType::parse_dice("1d6-9223372036854775808"), None);
+        assert!(RequestType::parse_dice("100d1000+1000000").is_some());
+    }
+
+    #[test]
+    fn finishes_dice() {
+        let dice = RequestType::parse_dice("3d6-2").unwrap();
+        let draws = ["1", "6", "3"].map(String::from);
+        assert_eq!(dice.finish(&draws), "8");
+        assert_eq!(dice.to_string(), "3d6-2");
+    }
+}
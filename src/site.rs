@@ -4,54 +4,71 @@ use axum::{
     extract::State,
     response::{Html, IntoResponse},
     routing::get,
-    Router,
+    Json, Router,
 };
-use axum_extra::extract::Host;
-use redis::{cmd, AsyncCommands as _};
+use axum_extra::extract::{Host, Query, SignedCookieJar};
 use rinja::Template;
-use uuid::Uuid;
+use serde::Deserialize;
 
-use crate::{error::RrgError, state::AppState};
+use crate::{
+    bank,
+    error::RrgError,
+    leaderboard::{self, Window},
+    provider::{self, Standing},
+    quality::{self, Report},
+    state::AppState,
+    waiter::{self, Waiter},
+};
+
+// The waitlist shows at most this many of the longest waiting
+const WAITLIST_SIZE: usize = 100;
 
 #[tracing::instrument]
-async fn get_pending(redis: Arc<deadpool_redis::Pool>) -> Result<Vec<Uuid>, RrgError> {
-    // get all members pending_callbacks list
+async fn get_pending(redis: Arc<deadpool_redis::Pool>) -> Result<Vec<Waiter>, RrgError> {
     let mut conn = redis
         .get()
         .await
         .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
-    // let pending_requests = conn
-    //     .lrange("pending_callbacks", 0, -1)
-    //     .await
-    //     .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
-
-    let pending_requests = cmd("LRANGE")
-        .arg("pending_callbacks")
-        .arg(0)
-        .arg(-1)
-        .query_async(&mut conn)
+    waiter::pending(&mut conn, WAITLIST_SIZE)
+        .await
+        .map_err(RrgError::RenderingInternalError)
+}
+
+#[tracing::instrument]
+async fn get_banked(state: &AppState) -> Result<usize, RrgError> {
+    let mut conn = state
+        .redis
+        .get()
+        .await
+        .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
+    bank::size(&mut conn, state.bank_expiry)
         .await
-        .unwrap();
-    Ok(pending_requests)
+        .map_err(RrgError::RenderingInternalError)
 }
 
 #[tracing::instrument]
 async fn index(
     Host(host): Host,
+    jar: SignedCookieJar,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, RrgError> {
     #[derive(Template)]
     #[template(path = "index.html")]
     struct IndexTemplate {
-        pending_requests: Vec<Uuid>,
+        pending_requests: Vec<Waiter>,
+        banked: usize,
+        handle: Option<String>,
         host: String,
     }
 
+    let banked = get_banked(&state).await?;
     let pending_requests = get_pending(state.redis).await?;
 
     Ok(Html(
         IndexTemplate {
             pending_requests,
+            banked,
+            handle: provider::claimed_handle(&jar),
             host,
         }
         .render()
@@ -59,35 +76,82 @@ async fn index(
     ))
 }
 
+#[derive(Deserialize, Debug)]
+struct StatsParams {
+    window: Option<Window>,
+    // Starting from 1
+    page: Option<usize>,
+}
+
 #[tracing::instrument]
-async fn get_top_n(
-    redis: Arc<deadpool_redis::Pool>,
-    n: isize,
-) -> Result<Vec<(String, f64)>, RrgError> {
-    let mut conn = redis
+async fn stats(
+    Query(StatsParams { window, page }): Query<StatsParams>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, RrgError> {
+    #[derive(Template)]
+    #[template(path = "stats.html")]
+    struct StatsTemplate {
+        window: Window,
+        windows: [Window; 4],
+        top_n: Vec<(String, u64)>,
+        providers: Vec<Standing>,
+        // Rank of the first number on the page
+        first_rank: usize,
+        previous_page: Option<usize>,
+        next_page: Option<usize>,
+    }
+
+    let window = window.unwrap_or_default();
+    let page = page.unwrap_or(1);
+    let offset = leaderboard::offset(page, state.leaderboard_size)
+        .map_err(|e| RrgError::BadRequest(e.to_string()))?;
+
+    let mut conn = state
+        .redis
         .get()
         .await
         .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
-    // Get the top n values with the highest scores along with their scores
-    let top_n = conn
-        .zrevrange_withscores("counts", 0, n)
+    let top_n = leaderboard::top(&mut conn, window, offset, state.leaderboard_size)
         .await
-        .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
-    Ok(top_n)
+        .map_err(RrgError::RenderingInternalError)?;
+    let providers = provider::leaderboard(&mut conn, state.leaderboard_size)
+        .await
+        .map_err(RrgError::RenderingInternalError)?;
+
+    Ok(Html(
+        StatsTemplate {
+            window,
+            windows: Window::ALL,
+            first_rank: offset + 1,
+            previous_page: (page > 1).then(|| page - 1),
+            next_page: (offset + top_n.entries.len() < top_n.total).then(|| page + 1),
+            top_n: top_n.entries,
+            providers,
+        }
+        .render()
+        .map_err(|e| RrgError::RenderingInternalError(e.into()))?,
+    ))
 }
 
 #[tracing::instrument]
-async fn stats(State(state): State<AppState>) -> Result<impl IntoResponse, RrgError> {
+async fn quality_report(State(state): State<AppState>) -> Result<impl IntoResponse, RrgError> {
     #[derive(Template)]
-    #[template(path = "stats.html")]
-    struct StatsTemplate {
-        top_n: Vec<(String, f64)>,
+    #[template(path = "quality.html")]
+    struct QualityTemplate {
+        report: Report,
     }
 
-    let top_10 = get_top_n(state.redis, 9).await?;
+    let mut conn = state
+        .redis
+        .get()
+        .await
+        .map_err(|e| RrgError::RenderingInternalError(e.into()))?;
+    let report = quality::report(&mut conn)
+        .await
+        .map_err(RrgError::RenderingInternalError)?;
 
     Ok(Html(
-        StatsTemplate { top_n: top_10 }
+        QualityTemplate { report }
             .render()
             .map_err(|e| RrgError::RenderingInternalError(e.into()))?,
     ))
@@ -106,9 +170,19 @@ async fn about() -> Result<impl IntoResponse, RrgError> {
     ))
 }
 
+// For checking receipts without asking us
+async fn signing_key(State(state): State<AppState>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "algorithm": "ed25519",
+        "public_key": state.signer.public_key(),
+    }))
+}
+
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route("/", get(index))
+        .route("/.well-known/rrg-signing-key", get(signing_key))
         .route("/stats", get(stats))
+        .route("/stats/quality", get(quality_report))
         .route("/about", get(about))
 }
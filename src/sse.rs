@@ -0,0 +1,279 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::response::{
+    sse::{Event, KeepAlive, Sse},
+    IntoResponse as _, Response,
+};
+use futures_util::stream;
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    state::{AppState, Callback},
+    submission::{now_millis, Delivery, Submission},
+    waiter::{self, Deadline, Listener, Outcome, Waiter},
+    websocket,
+};
+
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+struct Status {
+    // None once the waiter has everything it needs
+    position: Option<usize>,
+    watchers: u64,
+    received: usize,
+    needed: usize,
+    elapsed_ms: u64,
+}
+
+/// Id of the final event of a stream. A client that reconnects with it as
+/// `Last-Event-ID` has heard how its wait ended.
+pub const FINISHED_EVENT_ID: &str = "finished";
+
+// The final event of a stream, kept for a while in case the client reconnects
+// without having heard it
+fn finished_key(guid: Uuid) -> String {
+    format!("waiter:{guid}:finished")
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Finished {
+    event: String,
+    data: String,
+}
+
+/// Stream `status` events while the waiter waits, then a final `number` event
+/// with its delivery, `cancelled` if the requester cancelled it, or `expired`
+/// if it ran out of time and the fallback policy couldn't fill it. Every event
+/// but the last carries the resume token as its id, so a client that
+/// reconnects with `Last-Event-ID` picks the same wait back up. The last one
+/// has [`FINISHED_EVENT_ID`] instead, and is replayed to a client that
+/// reconnects within the resume grace period without having heard it. A
+/// secret generated for the waiter is sent first in a `created` event.
+pub fn wait(
+    state: AppState,
+    waiter: Waiter,
+    token: String,
+    secret: Option<String>,
+    deadline: Deadline,
+) -> Response {
+    let (events, rx) = mpsc::channel(8);
+
+    tokio::spawn(async move {
+        let guid = waiter.id;
+        if let Some(secret) = secret {
+            // A client that is already gone is noticed while following
+            let created = Event::default()
+                .event("created")
+                .id(&token)
+                .json_data(serde_json::json!({ "id": guid, "secret": secret }));
+            match created {
+                Ok(created) => _ = events.send(created).await,
+                Err(e) => tracing::error!("Unable to send secret to {guid}: {e:?}"),
+            }
+        }
+        if let Err(e) = follow(&state, &waiter, &token, deadline, &events).await {
+            tracing::error!("Error while streaming events for {guid}: {e:?}");
+        }
+    });
+
+    let stream = stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, Infallible>(event), rx))
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// A stream with just the `number` event, for a waiter that never had to wait
+pub async fn delivered(
+    conn: &mut deadpool_redis::Connection,
+    state: &AppState,
+    waiter: &Waiter,
+    submissions: &[Submission],
+) -> anyhow::Result<Response> {
+    let delivery = Delivery::new(waiter, submissions, None, &state.signer);
+    let event = finish(conn, state, waiter.id, "number", &delivery).await?;
+    Ok(single(event))
+}
+
+/// The final event of a stream that finished while its client was away, if it
+/// is still kept
+pub async fn replay(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+) -> anyhow::Result<Option<Response>> {
+    let finished: Option<String> = conn.get(finished_key(guid)).await?;
+    let Some(finished) = finished else {
+        return Ok(None);
+    };
+    let Finished { event, data } = serde_json::from_str(&finished)?;
+    tracing::debug!("Replaying the {event} event for {guid}");
+    Ok(Some(single(
+        Event::default()
+            .event(event)
+            .id(FINISHED_EVENT_ID)
+            .data(data),
+    )))
+}
+
+fn single(event: Event) -> Response {
+    Sse::new(stream::once(async { Ok::<_, Infallible>(event) })).into_response()
+}
+
+// Keep the final event before it is sent, so it can be replayed if the client
+// drops before hearing it
+async fn finish(
+    conn: &mut deadpool_redis::Connection,
+    state: &AppState,
+    guid: Uuid,
+    event: &str,
+    data: &impl Serialize,
+) -> anyhow::Result<Event> {
+    let finished = Finished {
+        event: event.to_string(),
+        data: serde_json::to_string(data)?,
+    };
+    conn.set_ex::<_, _, ()>(
+        finished_key(guid),
+        serde_json::to_string(&finished)?,
+        state.resume_grace.as_secs(),
+    )
+    .await?;
+    Ok(Event::default()
+        .event(finished.event)
+        .id(FINISHED_EVENT_ID)
+        .data(finished.data))
+}
+
+async fn follow(
+    state: &AppState,
+    waiter: &Waiter,
+    token: &str,
+    deadline: Deadline,
+    events: &mpsc::Sender<Event>,
+) -> anyhow::Result<()> {
+    let guid = waiter.id;
+
+    let (listener, mut notifications) = waiter::listen(state, guid);
+
+    let over = async {
+        let mut conn = state.redis.get().await?;
+        waiter::attach(&mut conn, &listener, state.resume_grace).await?;
+        watch(
+            &mut conn,
+            state,
+            waiter,
+            token,
+            deadline,
+            events,
+            &listener,
+            &mut notifications,
+        )
+        .await
+    }
+    .await
+    // Otherwise the waiter would stay registered with nobody to hear from it
+    .unwrap_or_else(|e| {
+        tracing::error!("Lost track of {guid}, treating it as disconnected: {e:?}");
+        false
+    });
+
+    // Another connection that resumed the waiter is in charge of it now
+    if !listener.stop() || over {
+        return Ok(());
+    }
+    // The client went away, give it a chance to come back before giving up its
+    // place
+    waiter::disconnected(state, &listener, true).await
+}
+
+// Returns whether the wait is over, which is false if the client disconnected
+// first. It is also over for a stream that another connection took the waiter
+// from, without it hearing how it ended.
+#[allow(clippy::too_many_arguments)]
+async fn watch(
+    conn: &mut deadpool_redis::Connection,
+    state: &AppState,
+    waiter: &Waiter,
+    token: &str,
+    deadline: Deadline,
+    events: &mpsc::Sender<Event>,
+    listener: &Listener,
+    notifications: &mut mpsc::UnboundedReceiver<Callback>,
+) -> anyhow::Result<bool> {
+    let guid = waiter.id;
+    let needed = waiter.needed();
+    let expires_at = deadline.expires_at(waiter);
+
+    // Some numbers may have arrived while a resuming client was away
+    let mut received = waiter::values(conn, guid).await?.len();
+
+    let mut interval = tokio::time::interval(STATUS_INTERVAL);
+    // Lets other instances know the client is still here, however short the
+    // grace period is
+    let mut hold_interval = tokio::time::interval(waiter::hold_interval(state.resume_grace));
+    let mut timed_out = false;
+    while received < needed {
+        tokio::select! {
+            Some(notification) = notifications.recv() => match notification {
+                Callback::Received(notification) => received = received.max(notification),
+                Callback::Cancelled => {
+                    // Whoever cancelled it already cleaned up
+                    let event = Event::default()
+                        .event("cancelled")
+                        .id(FINISHED_EVENT_ID)
+                        .data("cancelled");
+                    _ = events.send(event).await;
+                    return Ok(true);
+                }
+                Callback::Superseded => return Ok(true),
+            },
+            _ = hold_interval.tick() => {
+                // A connection to another instance may have resumed it
+                if !waiter::hold(conn, listener, state.resume_grace).await? {
+                    return Ok(true);
+                }
+            }
+            _ = interval.tick() => {
+                let status = Status {
+                    position: waiter::position(conn, guid).await?,
+                    watchers: websocket::watchers(conn).await?,
+                    received,
+                    needed,
+                    elapsed_ms: now_millis().saturating_sub(waiter.created_at),
+                };
+                let event = Event::default()
+                    .event("status")
+                    .id(token)
+                    .json_data(status)?;
+                if events.send(event).await.is_err() {
+                    return Ok(false);
+                }
+            }
+            () = events.closed() => return Ok(false),
+            () = tokio::time::sleep_until(expires_at) => {
+                timed_out = true;
+                break;
+            }
+        }
+    }
+
+    if !listener.stop() || !waiter::hold(conn, listener, state.resume_grace).await? {
+        return Ok(true);
+    }
+    // Either way the wait is over, whether or not the client hears about it
+    let event = match waiter::conclude(conn, state, waiter, timed_out, deadline).await? {
+        Outcome::Delivered(delivery) => finish(conn, state, guid, "number", &delivery).await?,
+        Outcome::Expired(expired) => finish(conn, state, guid, "expired", &expired).await?,
+        // A client that reconnects hears about it from whoever did conclude it
+        Outcome::Gone => return Ok(true),
+    };
+    _ = events.send(event).await;
+    Ok(true)
+}
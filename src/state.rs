@@ -1,31 +1,87 @@
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{Arc, Mutex, OnceLock},
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{broadcast, oneshot};
+use tokio::sync::{broadcast, mpsc};
 use uuid::Uuid;
 
+use crate::{
+    banned_list::Reloader, fallback::FallbackPolicy, priority::ApiKeys, provider::CookieKey,
+    receipt::Signer, waiter::Waiter,
+};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum StateUpdate {
-    Added(Uuid),
+    Added(Waiter),
+    // A waiter that needs several submissions received one of them
+    Updated(Waiter),
     Removed(Uuid),
 }
 
-// naughty numbers
-pub static BANNED_NUMBERS: OnceLock<HashSet<String>> = OnceLock::new();
+/// Sent over the "callbacks" channel to whichever instance holds the waiter's
+/// connection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Callback {
+    // The number of submissions the waiter has received so far
+    Received(usize),
+    // The requester gave up on the waiter
+    Cancelled,
+    // Another connection to this instance resumed the waiter, only sent locally
+    Superseded,
+}
 
-pub type CallbackMap = BTreeMap<Uuid, oneshot::Sender<String>>;
+pub type CallbackMap = BTreeMap<Uuid, mpsc::UnboundedSender<Callback>>;
 
 #[derive(Clone, Debug)]
 pub struct AppState {
     pub redis: Arc<deadpool_redis::Pool>,
 
-    // Allow submitted numbers to be sent to tasks that are awaiting a number by mapping the uuid
-    // of the origin request to a response channel
+    // Allow tasks that are awaiting a number to be notified of new submissions by mapping the uuid
+    // of the origin request to a notification channel
     pub callback_map: Arc<Mutex<CallbackMap>>,
 
     // State updates sent to all open websocket connections
     pub state_updates: Arc<broadcast::Sender<String>>,
+
+    // Salt for hashing provider addresses into anonymous handles
+    pub handle_salt: Arc<SecretString>,
+
+    // Whether client addresses come from the fly.io proxy's header
+    pub behind_fly_proxy: bool,
+
+    // Signs receipts for every delivery
+    pub signer: Arc<Signer>,
+
+    // Signs provider session cookies
+    pub cookie_key: CookieKey,
+
+    // How long /api/get waits for numbers by default, the longest a client may ask it to
+    // wait, and what it does if they don't arrive in time
+    pub request_timeout: Duration,
+    pub max_wait: Duration,
+    pub fallback_policy: FallbackPolicy,
+
+    // API keys that are allowed to queue at a higher priority
+    pub api_keys: Arc<ApiKeys>,
+
+    // How many numbers are on each page of the stats leaderboards
+    pub leaderboard_size: usize,
+
+    // How long a banked number can wait for someone to ask for it
+    pub bank_expiry: Duration,
+
+    // How long a ticket waits for numbers, and how long its numbers are kept once it has them
+    pub ticket_timeout: Duration,
+    pub ticket_retention: Duration,
+
+    // How long a disconnected client has to come back before it loses its place in the queue
+    pub resume_grace: Duration,
+
+    // Where the moderation rules come from, which admins can ask to reload
+    pub banned_list: Arc<Reloader>,
+    pub admin_token: Option<Arc<SecretString>>,
 }
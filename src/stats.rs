@@ -0,0 +1,105 @@
+//! Summary figures about submissions and the requests they fulfilled.
+
+use serde::Serialize;
+
+use crate::{leaderboard, submission::now_millis, waiter::Waiter};
+
+const SUBMISSIONS: &str = "stats:submissions";
+// HyperLogLog of every number submitted, approximate but tiny
+const DISTINCT: &str = "stats:distinct";
+const FULFILLED: &str = "stats:fulfilled";
+// How long recently fulfilled requests waited in milliseconds, newest first
+const WAITS: &str = "stats:waits";
+const WAIT_SAMPLES: isize = 1000;
+
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub total_submissions: u64,
+    pub distinct_numbers: u64,
+    pub fulfilled_requests: u64,
+    // Over the most recently fulfilled requests, None before the first one
+    pub median_wait_ms: Option<u64>,
+}
+
+/// Count a submitted number, whether it was delivered or banked
+pub async fn submitted(conn: &mut deadpool_redis::Connection, value: &str) -> anyhow::Result<()> {
+    tally(value, now_millis() / 1000)?
+        .query_async::<()>(conn)
+        .await?;
+    Ok(())
+}
+
+// Numbers go on the leaderboards along with the totals, so the two always agree
+fn tally(value: &str, now: u64) -> anyhow::Result<redis::Pipeline> {
+    let mut pipe = redis::pipe();
+    pipe.incr(SUBMISSIONS, 1)
+        .ignore()
+        .pfadd(DISTINCT, value)
+        .ignore();
+    leaderboard::record(&mut pipe, value, now)?;
+    Ok(pipe)
+}
+
+/// Count a request that got every number it asked for
+pub async fn fulfilled(
+    conn: &mut deadpool_redis::Connection,
+    waiter: &Waiter,
+) -> anyhow::Result<()> {
+    redis::pipe()
+        .incr(FULFILLED, 1)
+        .ignore()
+        .lpush(WAITS, now_millis().saturating_sub(waiter.created_at))
+        .ignore()
+        .ltrim(WAITS, 0, WAIT_SAMPLES - 1)
+        .ignore()
+        .query_async::<()>(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn summary(conn: &mut deadpool_redis::Connection) -> anyhow::Result<Summary> {
+    let (total_submissions, distinct_numbers, fulfilled_requests, mut waits): (
+        Option<u64>,
+        u64,
+        Option<u64>,
+        Vec<u64>,
+    ) = redis::pipe()
+        .get(SUBMISSIONS)
+        .pfcount(DISTINCT)
+        .get(FULFILLED)
+        .lrange(WAITS, 0, -1)
+        .query_async(conn)
+        .await?;
+
+    waits.sort_unstable();
+    let median_wait_ms = match waits.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((waits[len / 2 - 1] + waits[len / 2]) / 2),
+        len => Some(waits[len / 2]),
+    };
+
+    Ok(Summary {
+        total_submissions: total_submissions.unwrap_or(0),
+        distinct_numbers,
+        fulfilled_requests: fulfilled_requests.unwrap_or(0),
+        median_wait_ms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_numbers_on_the_leaderboards_too() {
+        // Banked numbers are only ever counted here
+        let packed = tally("4", 0).unwrap().get_packed_pipeline();
+        let packed = String::from_utf8_lossy(&packed);
+        for key in [SUBMISSIONS, DISTINCT, "counts", "counts:300:0"] {
+            assert!(
+                packed.contains(&format!("${}\r\n{key}\r\n", key.len())),
+                "{key}"
+            );
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::HeaderMap;
+use secrecy::{ExposeSecret as _, SecretString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    fallback::FallbackPolicy,
+    receipt::{Receipt, SignedReceipt, Signer},
+    waiter::Waiter,
+};
+
+/// A number handed to a waiter, along with where and when it came from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Submission {
+    pub value: String,
+    pub provider: String,
+    // Public handle of the provider, if they chose one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<String>,
+    // Unix time in milliseconds
+    pub submitted_at: u64,
+}
+
+impl Submission {
+    pub fn new(value: String, provider: String) -> Self {
+        Self {
+            value,
+            provider,
+            handle: None,
+            submitted_at: now_millis(),
+        }
+    }
+}
+
+/// Everything a requester is told about a fulfilled request, other than
+/// plain text responses which are just the numbers
+#[derive(Serialize, Debug)]
+pub struct Delivery {
+    pub request_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numbers: Option<Vec<String>>,
+    pub waited_ms: u64,
+    // Unix time in milliseconds
+    pub fulfilled_at_ms: u64,
+    // Anonymous handles of everyone who contributed, in order of first contribution
+    pub providers: Vec<String>,
+    // How the request was completed when nobody submitted in time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<FallbackPolicy>,
+    // Proof for third parties that the numbers came from us
+    pub receipt: SignedReceipt,
+}
+
+impl Delivery {
+    pub fn new(
+        waiter: &Waiter,
+        submissions: &[Submission],
+        fallback: Option<FallbackPolicy>,
+        signer: &Signer,
+    ) -> Self {
+        let mut random_numbers = waiter.finish(submissions);
+        // Banked numbers may have been submitted before the request was made, and
+        // a fallback only fills the request once its deadline has passed
+        let mut fulfilled_at_ms = submissions
+            .iter()
+            .map(|submission| submission.submitted_at)
+            .max()
+            .unwrap_or_default()
+            .max(waiter.created_at);
+        if fallback.is_some() {
+            fulfilled_at_ms = fulfilled_at_ms.max(now_millis());
+        }
+        let mut providers = Vec::new();
+        for submission in submissions {
+            if !providers.contains(&submission.provider) {
+                providers.push(submission.provider.clone());
+            }
+        }
+        let receipt = signer.sign(Receipt::new(
+            waiter.id,
+            random_numbers.clone(),
+            fulfilled_at_ms,
+            &providers,
+            fallback,
+        ));
+        let (number, numbers) = if waiter.count == 1 {
+            (random_numbers.pop(), None)
+        } else {
+            (None, Some(random_numbers))
+        };
+
+        Self {
+            request_id: waiter.id,
+            number,
+            numbers,
+            waited_ms: fulfilled_at_ms.saturating_sub(waiter.created_at),
+            fulfilled_at_ms,
+            providers,
+            fallback,
+            receipt,
+        }
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+        .try_into()
+        .unwrap()
+}
+
+/// Address of the client that made a request. Behind the fly.io proxy the
+/// socket address is the proxy, and the proxy says who the client is. Anywhere
+/// else the header is whatever the client wants it to be, so it's only trusted
+/// when `behind_fly_proxy` is set.
+pub fn client_ip(addr: SocketAddr, headers: &HeaderMap, behind_fly_proxy: bool) -> IpAddr {
+    headers
+        .get("fly-client-ip")
+        .filter(|_| behind_fly_proxy)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.parse::<IpAddr>().ok())
+        .unwrap_or(addr.ip())
+}
+
+/// Stable handle for a provider that doesn't reveal who they are. Derived from
+/// the client address, salted so that it can't be reversed by hashing every
+/// possible IP.
+pub fn anonymous_handle(
+    salt: &SecretString,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    behind_fly_proxy: bool,
+) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt.expose_secret())
+        .chain_update(client_ip(addr, headers, behind_fly_proxy).to_string())
+        .finalize();
+    format!("anon-{}", hex::encode(&digest[..4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        priority::Priority,
+        request_type::RequestType,
+        waiter::{Constraint, Waiter},
+    };
+
+    fn waiter(created_at: u64) -> Waiter {
+        Waiter {
+            id: Uuid::nil(),
+            note: None,
+            nickname: None,
+            request_type: RequestType::default(),
+            constraint: Constraint::default(),
+            count: 1,
+            created_at,
+            ticket: false,
+            priority: Priority::default(),
+            received: 0,
+        }
+    }
+
+    fn submission(submitted_at: u64) -> Submission {
+        Submission {
+            submitted_at,
+            ..Submission::new("4".to_string(), "anon-1".to_string())
+        }
+    }
+
+    #[test]
+    fn delivers_banked_numbers_when_requested() {
+        let delivery = Delivery::new(
+            &waiter(2_000),
+            &[submission(1_000)],
+            None,
+            &Signer::generate(),
+        );
+        assert_eq!(delivery.fulfilled_at_ms, 2_000);
+        assert_eq!(delivery.receipt.receipt.fulfilled_at_ms, 2_000);
+        assert_eq!(delivery.waited_ms, 0);
+    }
+
+    #[test]
+    fn delivers_submitted_numbers_when_the_last_arrived() {
+        let submissions = [submission(3_000), submission(5_000)];
+        let delivery = Delivery::new(&waiter(2_000), &submissions, None, &Signer::generate());
+        assert_eq!(delivery.fulfilled_at_ms, 5_000);
+        assert_eq!(delivery.waited_ms, 3_000);
+    }
+
+    #[test]
+    fn delivers_fallback_numbers_now() {
+        let before = now_millis();
+        let delivery = Delivery::new(
+            &waiter(2_000),
+            &[submission(1_000)],
+            Some(FallbackPolicy::Pool),
+            &Signer::generate(),
+        );
+        assert!(delivery.fulfilled_at_ms >= before);
+    }
+
+    #[test]
+    fn trusts_the_proxy_only_behind_it() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 443));
+        let mut headers = HeaderMap::new();
+        headers.insert("fly-client-ip", "203.0.113.7".parse().unwrap());
+
+        assert_eq!(
+            client_ip(addr, &headers, true),
+            IpAddr::from([203, 0, 113, 7])
+        );
+        assert_eq!(client_ip(addr, &headers, false), addr.ip());
+        assert_eq!(client_ip(addr, &HeaderMap::new(), true), addr.ip());
+    }
+}
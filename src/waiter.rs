@@ -0,0 +1,1002 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+
+use redis::{AsyncCommands as _, ExistenceCheck, SetExpiry, SetOptions};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    bank,
+    error::RrgError,
+    fallback::{self, Expired, FallbackPolicy},
+    priority::Priority,
+    request_type::RequestType,
+    state::{AppState, Callback, CallbackMap, StateUpdate},
+    stats,
+    submission::{now_millis, Delivery, Submission},
+};
+
+// Ordered list of waiter guids, newest at the head
+const PENDING: &str = "pending_callbacks";
+
+// The same for just the waiters of one priority class, so the oldest of each
+// class can be found however far back it is in the queue as a whole
+fn class_key(priority: Priority) -> String {
+    format!("pending_callbacks:{priority}")
+}
+
+// Take a waiter out of the queue and out of its class
+fn dequeue(guid: Uuid) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic().lrem(PENDING, 1, guid);
+    for priority in Priority::ALL {
+        pipe.lrem(class_key(priority), 1, guid).ignore();
+    }
+    pipe
+}
+
+fn waiter_key(guid: Uuid) -> String {
+    format!("waiter:{guid}")
+}
+
+// Numbers submitted so far for a waiter, in order of arrival
+fn values_key(guid: Uuid) -> String {
+    format!("waiter:{guid}:values")
+}
+
+// Id of the client connection that is waiting on the waiter, while there is one
+fn attached_key(guid: Uuid) -> String {
+    format!("waiter:{guid}:attached")
+}
+
+// Hash of the secret that allows the requester to cancel the waiter
+fn secret_key(guid: Uuid) -> String {
+    format!("waiter:{guid}:secret")
+}
+
+// Store a number for a waiter if it is still in the queue, and take it out of
+// the queue once it has all the numbers it needs. Done in one script so that
+// two providers can never both fill the last slot. Returns the number of values
+// the waiter now has, or 0 if it was no longer waiting.
+//
+// Values expire along with the waiter, and once the waiter is complete they
+// are kept around for the retention period along with the waiter and its
+// secret, so tickets can be collected.
+static CLAIM_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        if not redis.call('LPOS', KEYS[1], ARGV[1]) then
+            return 0
+        end
+        if redis.call('EXISTS', KEYS[3]) == 0 then
+            redis.call('LREM', KEYS[1], 0, ARGV[1])
+            redis.call('LREM', KEYS[5], 0, ARGV[1])
+            return 0
+        end
+        local received = redis.call('RPUSH', KEYS[2], ARGV[2])
+        local ttl = redis.call('PTTL', KEYS[3])
+        if ttl > 0 then
+            redis.call('PEXPIRE', KEYS[2], ttl)
+        end
+        if received >= tonumber(ARGV[3]) then
+            redis.call('LREM', KEYS[1], 1, ARGV[1])
+            redis.call('LREM', KEYS[5], 1, ARGV[1])
+            redis.call('PEXPIRE', KEYS[2], ARGV[4])
+            redis.call('PEXPIRE', KEYS[3], ARGV[4])
+            redis.call('PEXPIRE', KEYS[4], ARGV[4])
+        end
+        return received
+        ",
+    )
+});
+
+// Refresh a connection's hold on a waiter, or take it if no connection has it.
+// Returns 0 if another connection has it.
+static HOLD_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local connection = redis.call('GET', KEYS[1])
+        if connection and connection ~= ARGV[1] then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+        return 1
+        ",
+    )
+});
+
+// Let go of a waiter unless another connection already took it over
+static DETACH_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    )
+});
+
+/// Restrictions a client places on the numbers it is willing to receive.
+/// A constraint with no bounds and no exclusions accepts anything at all,
+/// otherwise a submission must parse as an integer that satisfies every rule.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Constraint {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    #[serde(default)]
+    pub not: Vec<i64>,
+    // Require an integer even when there are no other rules
+    #[serde(default)]
+    pub integer: bool,
+}
+
+impl Constraint {
+    pub fn new(min: Option<i64>, max: Option<i64>, not: Vec<i64>) -> Result<Self, RrgError> {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(RrgError::BadRequest(format!(
+                    "min ({min}) must not be greater than max ({max})"
+                )));
+            }
+
+            // Don't let anyone wait forever for a number nobody is allowed to give them
+            let excluded = not
+                .iter()
+                .filter(|n| (min..=max).contains(*n))
+                .collect::<HashSet<_>>()
+                .len();
+            if i128::from(max) - i128::from(min) < excluded as i128 {
+                return Err(RrgError::BadRequest(
+                    "Every number in the requested range is excluded".to_string(),
+                ));
+            }
+        }
+
+        Ok(Self {
+            min,
+            max,
+            not,
+            integer: false,
+        })
+    }
+
+    pub fn range(min: i64, max: i64) -> Self {
+        Self {
+            min: Some(min),
+            max: Some(max),
+            not: Vec::new(),
+            integer: true,
+        }
+    }
+
+    pub fn is_unconstrained(&self) -> bool {
+        !self.integer && self.min.is_none() && self.max.is_none() && self.not.is_empty()
+    }
+
+    pub fn accepts(&self, random_number: &str) -> bool {
+        if self.is_unconstrained() {
+            return true;
+        }
+
+        let Ok(number) = random_number.trim().parse::<i64>() else {
+            return false;
+        };
+
+        self.min.is_none_or(|min| number >= min)
+            && self.max.is_none_or(|max| number <= max)
+            && !self.not.contains(&number)
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rules = Vec::new();
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => rules.push(format!("{min} to {max}")),
+            (Some(min), None) => rules.push(format!("at least {min}")),
+            (None, Some(max)) => rules.push(format!("at most {max}")),
+            (None, None) => {}
+        }
+        if !self.not.is_empty() {
+            let excluded = self
+                .not
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            rules.push(format!("not {excluded}"));
+        }
+        if rules.is_empty() && self.integer {
+            rules.push("any integer".to_string());
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+/// A client that is waiting for a random number
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Waiter {
+    pub id: Uuid,
+    // Moderated text from the requester for providers to see
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub nickname: Option<String>,
+    #[serde(default)]
+    pub request_type: RequestType,
+    // Applies to each individual submission
+    #[serde(default)]
+    pub constraint: Constraint,
+    // How many values of request_type to return
+    #[serde(default = "default_count")]
+    pub count: usize,
+    // Unix time in milliseconds
+    #[serde(default)]
+    pub created_at: u64,
+    // Tickets are collected by polling rather than over a held connection
+    #[serde(default)]
+    pub ticket: bool,
+    #[serde(default)]
+    pub priority: Priority,
+    // Submissions received so far, only filled in when reading the queue
+    #[serde(default)]
+    pub received: usize,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+impl Waiter {
+    /// How many submissions this waiter needs before it can be answered
+    pub fn needed(&self) -> usize {
+        self.request_type.draws() * self.count
+    }
+
+    /// Turn every submission this waiter received into its final values
+    pub fn finish(&self, submissions: &[Submission]) -> Vec<String> {
+        submissions
+            .chunks(self.request_type.draws())
+            .map(|draws| {
+                let draws = draws
+                    .iter()
+                    .map(|submission| submission.value.clone())
+                    .collect::<Vec<_>>();
+                self.request_type.finish(&draws)
+            })
+            .collect()
+    }
+
+    /// Tells providers what this waiter is willing to accept
+    pub fn describe(&self) -> Option<String> {
+        match self.request_type {
+            RequestType::Any | RequestType::Int => {
+                (!self.constraint.is_unconstrained()).then(|| self.constraint.to_string())
+            }
+            _ => Some(format!("{}: {}", self.request_type, self.constraint)),
+        }
+    }
+}
+
+/// Add a waiter to the back of the queue and let providers know about it.
+/// With an expiry the waiter is dropped from the queue if it isn't fulfilled
+/// in time, otherwise it stays until it is removed. With a secret only the
+/// requester can act on it later. Fails if the id is already taken, so nobody
+/// can take over someone else's waiter by reusing its id.
+pub async fn register(
+    conn: &mut deadpool_redis::Connection,
+    waiter: &Waiter,
+    secret: Option<&str>,
+    expiry: Option<Duration>,
+) -> Result<(), RrgError> {
+    let mut options = SetOptions::default().conditional_set(ExistenceCheck::NX);
+    if let Some(expiry) = expiry {
+        options = options.with_expiration(SetExpiry::EX(expiry.as_secs()));
+    }
+    let value = serde_json::to_string(waiter).map_err(anyhow::Error::from)?;
+    let registered: bool = conn
+        .set_options(waiter_key(waiter.id), value, options)
+        .await
+        .map_err(anyhow::Error::from)?;
+    if !registered {
+        return Err(RrgError::Conflict(format!(
+            "A request with id {} already exists",
+            waiter.id
+        )));
+    }
+
+    if let Some(secret) = secret {
+        protect(conn, waiter.id, secret, expiry).await?;
+    }
+    redis::pipe()
+        .atomic()
+        .lpush(PENDING, waiter.id)
+        .ignore()
+        .lpush(class_key(waiter.priority), waiter.id)
+        .ignore()
+        .query_async::<()>(conn)
+        .await
+        .map_err(anyhow::Error::from)?;
+    conn.publish::<_, _, ()>(
+        "state_updates",
+        serde_json::to_string(&StateUpdate::Added(waiter.clone())).map_err(anyhow::Error::from)?,
+    )
+    .await
+    .map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+// Shortest resume token that is hard enough to guess
+const MIN_RESUME_TOKEN_LENGTH: usize = 16;
+
+/// The waiter id for a client-chosen resume token. Ids are shown on the
+/// waitlist, so the id is a hash that doesn't give the token away.
+pub fn resume_id(token: &str) -> Result<Uuid, RrgError> {
+    if token.len() < MIN_RESUME_TOKEN_LENGTH {
+        return Err(RrgError::BadRequest(format!(
+            "resume must be at least {MIN_RESUME_TOKEN_LENGTH} characters"
+        )));
+    }
+    // Also sent back as an event id, which can't hold line breaks
+    if token.chars().any(char::is_control) {
+        return Err(RrgError::BadRequest(
+            "resume must not contain control characters".to_string(),
+        ));
+    }
+    let digest = Sha256::digest(token.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    Ok(uuid::Builder::from_random_bytes(bytes).into_uuid())
+}
+
+// Only allow the waiter to be cancelled by someone who knows the secret
+async fn protect(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+    secret: &str,
+    expiry: Option<Duration>,
+) -> anyhow::Result<()> {
+    let hash = hex::encode(Sha256::digest(secret.as_bytes()));
+    match expiry {
+        Some(expiry) => {
+            conn.set_ex::<_, _, ()>(secret_key(guid), hash, expiry.as_secs())
+                .await?;
+        }
+        None => conn.set::<_, _, ()>(secret_key(guid), hash).await?,
+    }
+    Ok(())
+}
+
+/// Make sure the secret is the one the waiter was protected with. Nobody can
+/// act on a waiter that wasn't protected.
+pub async fn check_secret(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+    secret: &str,
+) -> Result<(), RrgError> {
+    let hash: Option<String> = conn
+        .get(secret_key(guid))
+        .await
+        .map_err(anyhow::Error::from)?;
+    match hash {
+        Some(hash) if hash == hex::encode(Sha256::digest(secret.as_bytes())) => Ok(()),
+        Some(_) => Err(RrgError::Forbidden("Wrong secret".to_string())),
+        None => Err(RrgError::Forbidden(
+            "This request was made without a secret".to_string(),
+        )),
+    }
+}
+
+/// Look up a waiter, whether it is still in the queue or already fulfilled
+pub async fn get(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+) -> anyhow::Result<Option<Waiter>> {
+    let waiter: Option<String> = conn.get(waiter_key(guid)).await?;
+    Ok(waiter
+        .map(|waiter| serde_json::from_str(&waiter))
+        .transpose()?)
+}
+
+/// Take a waiter out of the queue so that nobody can claim it any more. Returns
+/// false if it was already gone, which means it was completed or removed.
+pub async fn withdraw(conn: &mut deadpool_redis::Connection, guid: Uuid) -> anyhow::Result<bool> {
+    let (removed,): (usize,) = dequeue(guid).query_async(conn).await?;
+    Ok(removed == 1)
+}
+
+/// Remove a waiter that its client gave up on. Any numbers it was holding go to
+/// the bank rather than being thrown away.
+pub async fn abandon(conn: &mut deadpool_redis::Connection, guid: Uuid) -> anyhow::Result<()> {
+    withdraw(conn, guid).await?;
+    let leftovers = values(conn, guid).await?;
+    remove(conn, guid).await?;
+    for submission in &leftovers {
+        bank::deposit(conn, submission).await?;
+    }
+    Ok(())
+}
+
+/// Remove a waiter from the queue, whether or not it has already been claimed
+pub async fn remove(conn: &mut deadpool_redis::Connection, guid: Uuid) -> anyhow::Result<()> {
+    dequeue(guid).query_async::<()>(conn).await?;
+    conn.del::<_, ()>(vec![
+        waiter_key(guid),
+        values_key(guid),
+        attached_key(guid),
+        secret_key(guid),
+    ])
+    .await?;
+    conn.publish::<_, _, ()>(
+        "state_updates",
+        serde_json::to_string(&StateUpdate::Removed(guid))?,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Try to give a number to a waiter. Returns how many numbers the waiter has
+/// received including this one, or None if another submission (or a
+/// disconnect, or expiry) took it out of the queue first. A waiter that this
+/// completes is kept for `retention`.
+pub async fn claim(
+    conn: &mut deadpool_redis::Connection,
+    waiter: &Waiter,
+    submission: &Submission,
+    retention: Duration,
+) -> anyhow::Result<Option<usize>> {
+    let received: usize = CLAIM_SCRIPT
+        .key(PENDING)
+        .key(values_key(waiter.id))
+        .key(waiter_key(waiter.id))
+        .key(secret_key(waiter.id))
+        .key(class_key(waiter.priority))
+        .arg(waiter.id)
+        .arg(serde_json::to_string(submission)?)
+        .arg(waiter.needed())
+        .arg(u64::try_from(retention.as_millis())?)
+        .invoke_async(conn)
+        .await?;
+    Ok((received > 0).then_some(received))
+}
+
+/// The numbers a waiter has received so far
+pub async fn values(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+) -> anyhow::Result<Vec<Submission>> {
+    let values: Vec<String> = conn.lrange(values_key(guid), 0, -1).await?;
+    Ok(values
+        .iter()
+        .map(|value| serde_json::from_str(value))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Mark a waiter as having the listener's client connected to it, for at most
+/// `ttl` unless held onto. Takes it over from any other connection, wherever
+/// that one is.
+pub async fn attach(
+    conn: &mut deadpool_redis::Connection,
+    listener: &Listener,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    conn.pset_ex::<_, _, ()>(
+        attached_key(listener.guid),
+        listener.connection,
+        u64::try_from(ttl.as_millis())?,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Keep the listener's client attached to a waiter for another `ttl`. Returns
+/// false if another connection took the waiter over, in which case this one
+/// must leave the waiter to it.
+pub async fn hold(
+    conn: &mut deadpool_redis::Connection,
+    listener: &Listener,
+    ttl: Duration,
+) -> anyhow::Result<bool> {
+    let held: u8 = HOLD_SCRIPT
+        .key(attached_key(listener.guid))
+        .arg(listener.connection)
+        .arg(u64::try_from(ttl.as_millis())?)
+        .invoke_async(conn)
+        .await?;
+    Ok(held == 1)
+}
+
+/// How often to hold onto a waiter for `ttl` at a time, so that the hold never
+/// lapses while the client is still connected
+pub fn hold_interval(ttl: Duration) -> Duration {
+    ttl / 3
+}
+
+pub async fn detach(
+    conn: &mut deadpool_redis::Connection,
+    listener: &Listener,
+) -> anyhow::Result<()> {
+    DETACH_SCRIPT
+        .key(attached_key(listener.guid))
+        .arg(listener.connection)
+        .invoke_async::<()>(conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn is_attached(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+) -> anyhow::Result<bool> {
+    Ok(conn.exists(attached_key(guid)).await?)
+}
+
+/// Where a waiter is in the queue, starting at 1 for the longest waiting, or
+/// None if it is no longer in the queue
+pub async fn position(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+) -> anyhow::Result<Option<usize>> {
+    // The queue is newest first
+    let index: Option<usize> = redis::cmd("LPOS")
+        .arg(PENDING)
+        .arg(guid)
+        .query_async(conn)
+        .await?;
+    let Some(index) = index else {
+        return Ok(None);
+    };
+    let len: usize = conn.llen(PENDING).await?;
+    Ok(Some(len.saturating_sub(index)))
+}
+
+/// How long to wait, counted from when the waiter was created so that
+/// reconnecting doesn't extend it, and what to do once it's over
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    pub wait: Duration,
+    pub policy: FallbackPolicy,
+}
+
+impl Deadline {
+    pub fn expires_at(&self, waiter: &Waiter) -> tokio::time::Instant {
+        let wait = u64::try_from(self.wait.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = waiter.created_at.saturating_add(wait);
+        tokio::time::Instant::now() + Duration::from_millis(expires_at.saturating_sub(now_millis()))
+    }
+}
+
+/// Hears about a waiter's submissions on this instance while a client is
+/// connected to it. Only the connection that listened last hears anything, so
+/// it is the only one that may finish or clean up the waiter. Connections on
+/// other instances are told apart by which of them is attached.
+#[derive(Clone, Debug)]
+pub struct Listener {
+    guid: Uuid,
+    connection: Uuid,
+    sender: mpsc::UnboundedSender<Callback>,
+    callback_map: Arc<Mutex<CallbackMap>>,
+}
+
+pub fn listen(state: &AppState, guid: Uuid) -> (Listener, mpsc::UnboundedReceiver<Callback>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let previous = state
+        .callback_map
+        .lock()
+        .unwrap()
+        .insert(guid, sender.clone());
+    if let Some(previous) = previous {
+        // Whoever was listening before has lost the waiter to us
+        _ = previous.send(Callback::Superseded);
+    }
+    let listener = Listener {
+        guid,
+        connection: Uuid::new_v4(),
+        sender,
+        callback_map: state.callback_map.clone(),
+    };
+    (listener, receiver)
+}
+
+impl Listener {
+    /// Stop listening. A resumed connection may have replaced our sender, in
+    /// which case that one is left alone and this returns false.
+    pub fn stop(&self) -> bool {
+        let mut callback_map = self.callback_map.lock().unwrap();
+        let ours = callback_map
+            .get(&self.guid)
+            .is_some_and(|sender| sender.same_channel(&self.sender));
+        if ours {
+            callback_map.remove(&self.guid);
+        }
+        ours
+    }
+}
+
+/// How a wait ended for a client that was there to hear it
+#[derive(Debug)]
+pub enum Outcome {
+    Delivered(Delivery),
+    // Out of time and the fallback policy couldn't fill it either
+    Expired(Expired),
+    // Another connection concluded it first, or the requester cancelled it
+    Gone,
+}
+
+/// Take a waiter that has everything it needs or ran out of time out of the
+/// queue. Out of time, stop anyone else from claiming it before seeing what it
+/// got. If it was already out of the queue then the last number arrived just
+/// in time, otherwise what's missing is filled in with the fallback policy, or
+/// whatever did arrive goes to the bank. A waiter that was taken out of the
+/// queue without everything it needs was concluded or cancelled by someone
+/// else, who has the numbers.
+pub async fn conclude(
+    conn: &mut deadpool_redis::Connection,
+    state: &AppState,
+    waiter: &Waiter,
+    timed_out: bool,
+    deadline: Deadline,
+) -> anyhow::Result<Outcome> {
+    let guid = waiter.id;
+    let fallback = timed_out && withdraw(conn, guid).await?;
+
+    let mut submissions = values(conn, guid).await?;
+    remove(conn, guid).await?;
+    if !fallback {
+        if submissions.len() < waiter.needed() {
+            return Ok(Outcome::Gone);
+        }
+        return Ok(Outcome::Delivered(Delivery::new(
+            waiter,
+            &submissions,
+            None,
+            &state.signer,
+        )));
+    }
+
+    let policy = deadline.policy;
+    let missing = waiter.needed().saturating_sub(submissions.len());
+    tracing::debug!("{guid} expired missing {missing} numbers, falling back to {policy}");
+    if let Some(filled) = fallback::fill(conn, policy, waiter, missing, state.bank_expiry).await? {
+        stats::fulfilled(conn, waiter).await?;
+        submissions.extend(filled);
+        return Ok(Outcome::Delivered(Delivery::new(
+            waiter,
+            &submissions,
+            Some(policy),
+            &state.signer,
+        )));
+    }
+
+    // Numbers that made it in are still good for someone else
+    for submission in &submissions {
+        bank::deposit(conn, submission).await?;
+    }
+    Ok(Outcome::Expired(Expired::new(guid, deadline.wait, policy)))
+}
+
+/// Clean up after a client that went away before its wait was over. One that
+/// can resume keeps its place, along with anything that arrives, for the grace
+/// period in case it comes back.
+pub async fn disconnected(
+    state: &AppState,
+    listener: &Listener,
+    resumable: bool,
+) -> anyhow::Result<()> {
+    let guid = listener.guid;
+    let mut conn = state.redis.get().await?;
+    if resumable {
+        detach(&mut conn, listener).await?;
+        drop(conn);
+        tokio::time::sleep(state.resume_grace).await;
+
+        conn = state.redis.get().await?;
+        if is_attached(&mut conn, guid).await? {
+            return Ok(());
+        }
+        tracing::debug!("{guid} did not resume, removing it");
+    }
+    abandon(&mut conn, guid).await
+}
+
+/// Up to `limit` of the longest waiting pending waiters, newest first
+pub async fn pending(
+    conn: &mut deadpool_redis::Connection,
+    limit: usize,
+) -> anyhow::Result<Vec<Waiter>> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    // The oldest are at the tail
+    let guids: Vec<Uuid> = conn.lrange(PENDING, -isize::try_from(limit)?, -1).await?;
+    read(conn, guids).await
+}
+
+/// How many waiters are in the queue
+pub async fn waiting(conn: &mut deadpool_redis::Connection) -> anyhow::Result<usize> {
+    Ok(conn.llen(PENDING).await?)
+}
+
+// Look up queued waiters along with how far along they are, in the same order
+async fn read(
+    conn: &mut deadpool_redis::Connection,
+    guids: Vec<Uuid>,
+) -> anyhow::Result<Vec<Waiter>> {
+    if guids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let keys = guids.iter().copied().map(waiter_key).collect::<Vec<_>>();
+    let waiters: Vec<Option<String>> = conn.mget(keys).await?;
+
+    let mut progress = redis::pipe();
+    for guid in &guids {
+        progress.llen(values_key(*guid));
+    }
+    let received: Vec<usize> = progress.query_async(conn).await?;
+
+    let mut pending = Vec::new();
+    for ((guid, waiter), received) in guids.into_iter().zip(waiters).zip(received) {
+        match waiter {
+            Some(waiter) => pending.push(Waiter {
+                received,
+                ..serde_json::from_str(&waiter)?
+            }),
+            // The waiter expired (or was removed between the two reads, in which case this is a
+            // no-op), so clear it out of the queue
+            None => remove(conn, guid).await?,
+        }
+    }
+    Ok(pending)
+}
+
+// How many waiters to read at once while looking for one that can use a number
+const PAGE_SIZE: usize = 100;
+
+// Waiters read from the queue, oldest first
+struct Page {
+    waiters: Vec<Waiter>,
+    // Nothing in the queue comes after this page
+    last: bool,
+}
+
+// Somewhere the waiters of a class can be read from, a page at a time starting
+// from the oldest
+trait Queue {
+    async fn page(&mut self, priority: Priority, start: usize, size: usize)
+        -> anyhow::Result<Page>;
+}
+
+impl Queue for deadpool_redis::Connection {
+    async fn page(
+        &mut self,
+        priority: Priority,
+        start: usize,
+        size: usize,
+    ) -> anyhow::Result<Page> {
+        // The oldest are at the tail
+        let stop = -isize::try_from(start)? - 1;
+        let guids: Vec<Uuid> = self
+            .lrange(class_key(priority), stop - isize::try_from(size)? + 1, stop)
+            .await?;
+        let last = guids.len() < size;
+        let mut waiters = read(self, guids).await?;
+        waiters.reverse();
+        Ok(Page { waiters, last })
+    }
+}
+
+/// The longest waiting waiters of each class that can use a number, newest
+/// first like the queue. Each class is read from the oldest until some are
+/// found, so nobody is passed over however far back they are, and the
+/// priority order gets to choose between every class.
+pub async fn accepting(
+    conn: &mut deadpool_redis::Connection,
+    random_number: &str,
+) -> anyhow::Result<Vec<Waiter>> {
+    find(conn, random_number).await
+}
+
+async fn find(queue: &mut impl Queue, random_number: &str) -> anyhow::Result<Vec<Waiter>> {
+    let mut found = Vec::new();
+    for priority in Priority::ALL {
+        found.extend(find_in(queue, priority, random_number).await?);
+    }
+    Ok(found)
+}
+
+async fn find_in(
+    queue: &mut impl Queue,
+    priority: Priority,
+    random_number: &str,
+) -> anyhow::Result<Vec<Waiter>> {
+    let mut start = 0;
+    loop {
+        let page = queue.page(priority, start, PAGE_SIZE).await?;
+        // Expired waiters were cleared out of the queue as they were read, which
+        // moved everyone after them forward
+        start += page.waiters.len();
+        let mut accepting = page
+            .waiters
+            .into_iter()
+            .filter(|waiter| waiter.constraint.accepts(random_number))
+            .collect::<Vec<_>>();
+        if !accepting.is_empty() || page.last {
+            accepting.reverse();
+            return Ok(accepting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Oldest first
+    impl Queue for Vec<Waiter> {
+        async fn page(
+            &mut self,
+            priority: Priority,
+            start: usize,
+            size: usize,
+        ) -> anyhow::Result<Page> {
+            let waiters = self
+                .iter()
+                .filter(|waiter| waiter.priority == priority)
+                .skip(start)
+                .take(size)
+                .cloned()
+                .collect::<Vec<_>>();
+            Ok(Page {
+                last: waiters.len() < size,
+                waiters,
+            })
+        }
+    }
+
+    fn waiter(constraint: Constraint) -> Waiter {
+        classed(Priority::default(), constraint)
+    }
+
+    fn classed(priority: Priority, constraint: Constraint) -> Waiter {
+        Waiter {
+            id: Uuid::now_v7(),
+            note: None,
+            nickname: None,
+            request_type: RequestType::default(),
+            constraint,
+            count: 1,
+            created_at: 0,
+            ticket: false,
+            priority,
+            received: 0,
+        }
+    }
+
+    fn ids(waiters: &[Waiter]) -> Vec<Uuid> {
+        waiters.iter().map(|waiter| waiter.id).collect()
+    }
+
+    #[test]
+    fn accepts_anything_without_rules() {
+        let constraint = Constraint::default();
+        assert!(constraint.is_unconstrained());
+        for number in ["4", " 4 ", "four", "", "99999999999999999999"] {
+            assert!(constraint.accepts(number), "{number:?}");
+        }
+    }
+
+    #[test]
+    fn accepts_integers_in_range() {
+        let constraint = Constraint::new(Some(-5), Some(5), vec![]).unwrap();
+        for number in ["-5", "0", "5", "+3", " 2", "2 ", "\t-1\n"] {
+            assert!(constraint.accepts(number), "{number:?}");
+        }
+        for number in ["-6", "6", "2.0", "2 2", "0x1", "", "two"] {
+            assert!(!constraint.accepts(number), "{number:?}");
+        }
+    }
+
+    #[test]
+    fn treats_negative_zero_as_zero() {
+        assert!(Constraint::new(Some(0), Some(0), vec![])
+            .unwrap()
+            .accepts("-0"));
+        assert!(!Constraint::new(None, None, vec![0]).unwrap().accepts("-0"));
+    }
+
+    #[test]
+    fn rejects_numbers_out_of_integer_range() {
+        let constraint = Constraint::new(Some(0), None, vec![]).unwrap();
+        assert!(constraint.accepts("9223372036854775807"));
+        assert!(!constraint.accepts("9223372036854775808"));
+        assert!(!Constraint::new(None, Some(0), vec![])
+            .unwrap()
+            .accepts("-9223372036854775809"));
+    }
+
+    #[test]
+    fn rejects_excluded_numbers() {
+        let constraint = Constraint::new(Some(1), Some(3), vec![2]).unwrap();
+        assert!(constraint.accepts("1"));
+        assert!(!constraint.accepts("2"));
+        assert!(!constraint.accepts(" 2 "));
+    }
+
+    #[test]
+    fn requires_an_integer_when_asked() {
+        let constraint = Constraint {
+            integer: true,
+            ..Constraint::default()
+        };
+        assert!(constraint.accepts("-12"));
+        assert!(!constraint.accepts("twelve"));
+    }
+
+    #[test]
+    fn rejects_impossible_constraints() {
+        assert!(Constraint::new(Some(2), Some(1), vec![]).is_err());
+        assert!(Constraint::new(Some(1), Some(2), vec![1, 2, 2]).is_err());
+        assert!(Constraint::new(Some(i64::MIN), Some(i64::MAX), vec![0]).is_ok());
+        assert!(Constraint::new(Some(1), Some(2), vec![1, 3]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn finds_the_oldest_waiters_that_can_use_a_number() {
+        let mut queue = (0..3)
+            .map(|_| waiter(Constraint::default()))
+            .collect::<Vec<_>>();
+        queue.insert(1, waiter(Constraint::range(5, 6)));
+        let found = find(&mut queue, "4").await.unwrap();
+        // Newest first, like the queue
+        assert_eq!(ids(&found), [queue[3].id, queue[2].id, queue[0].id]);
+    }
+
+    #[tokio::test]
+    async fn looks_past_the_first_page() {
+        let mut queue = (0..PAGE_SIZE * 2 + 10)
+            .map(|_| waiter(Constraint::range(0, 0)))
+            .collect::<Vec<_>>();
+        queue.push(waiter(Constraint::range(1, 6)));
+        let found = find(&mut queue, "4").await.unwrap();
+        assert_eq!(ids(&found), [queue.last().unwrap().id]);
+        assert!(find(&mut queue, "7").await.unwrap().is_empty());
+        assert!(find(&mut Vec::new(), "4").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finds_the_oldest_of_every_class() {
+        let mut queue = (0..PAGE_SIZE * 2)
+            .map(|_| waiter(Constraint::default()))
+            .collect::<Vec<_>>();
+        queue.push(classed(Priority::High, Constraint::default()));
+        queue.push(classed(Priority::High, Constraint::default()));
+        let found = find(&mut queue, "4").await.unwrap();
+        let high = found
+            .iter()
+            .filter(|waiter| waiter.priority == Priority::High)
+            .collect::<Vec<_>>();
+        assert_eq!(high.len(), 2);
+        // Every normal waiter in the first page, and none from behind it
+        assert_eq!(found.len(), PAGE_SIZE + 2);
+        assert!(found.iter().any(|waiter| waiter.id == queue[0].id));
+    }
+
+    #[test]
+    fn holds_well_within_the_grace_period() {
+        for grace in [1, 5, 30] {
+            let grace = Duration::from_secs(grace);
+            let interval = hold_interval(grace);
+            // Nonzero, and a missed refresh still leaves time for the next one
+            assert!(!interval.is_zero());
+            assert!(interval * 2 < grace);
+        }
+    }
+}
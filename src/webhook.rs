@@ -0,0 +1,567 @@
+//! Delivery of fulfilled tickets to a requester's callback URL.
+//!
+//! Deliveries go through an outbox in Redis so that they survive restarts and
+//! are retried with exponential backoff until the receiver accepts them. Each
+//! request is a JSON `POST` of the same body `/api/requests/{id}` returns to
+//! JSON clients, with headers:
+//!
+//! - `x-rrg-delivery`: unique id of the delivery, the same across retries
+//! - `x-rrg-timestamp`: Unix time in milliseconds the request was sent
+//! - `x-rrg-signature`: `sha256=` followed by the hex HMAC-SHA256 of
+//!   `{timestamp}.{body}`, keyed with the secret returned when the ticket was
+//!   created
+//!
+//! Callbacks may only go to public addresses, so that tickets can't be used to
+//! probe the network the server runs in. Host names are checked when the
+//! ticket is created and again each time they are resolved for delivery, and
+//! redirects aren't followed.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac as _};
+use redis::AsyncCommands as _;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    error::RrgError,
+    receipt::Signer,
+    submission::{now_millis, Delivery},
+    waiter::{self, Waiter},
+};
+
+// Delivery ids, scored by when they should next be attempted
+const OUTBOX: &str = "webhook_outbox";
+
+const MAX_ATTEMPTS: u32 = 8;
+const BATCH_SIZE: usize = 16;
+// How long a worker has to finish an attempt before another worker may retry it
+const LEASE: Duration = Duration::from_secs(60);
+// How long to remember the outcome of a finished delivery
+const HISTORY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Callback registered on a ticket that has not been fulfilled yet
+fn webhook_key(guid: Uuid) -> String {
+    format!("waiter:{guid}:webhook")
+}
+
+fn delivery_key(id: Uuid) -> String {
+    format!("webhook_delivery:{id}")
+}
+
+// Take every due delivery, pushing each back by the lease so that a worker that
+// dies mid-attempt doesn't lose it
+static LEASE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r"
+        local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[3])
+        for _, id in ipairs(due) do
+            redis.call('ZADD', KEYS[1], ARGV[2], id)
+        end
+        return due
+        ",
+    )
+});
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Webhook {
+    url: String,
+    secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OutboxEntry {
+    url: String,
+    secret: String,
+    body: String,
+    attempts: u32,
+    status: Status,
+}
+
+// Whether an address can be reached from the internet, rather than being the
+// server itself, a private network, cloud metadata and the like
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64)
+                // Protocol assignments, 192.0.0.0/24
+                || ip.octets()[..3] == [192, 0, 0]
+                // Benchmarking, 198.18.0.0/15
+                || (first == 198 && second & 0xfe == 18)
+                // Reserved, 240.0.0.0/4
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(ip));
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, which includes Fly's private network
+                || first & 0xfe00 == 0xfc00
+                // Link local
+                || first & 0xffc0 == 0xfe80
+                // Discard, 100::/64
+                || ip.segments()[..4] == [0x100, 0, 0, 0]
+                // Local NAT64, 64:ff9b:1::/48
+                || (first == 0x64 && second == 0xff9b)
+                // Teredo, 2001::/32, and documentation, 2001:db8::/32
+                || (first == 0x2001 && matches!(second, 0 | 0xdb8))
+                // Documentation, 3fff::/20
+                || first & 0xfff0 == 0x3ff0)
+        }
+    }
+}
+
+// The IPv4 address that an IPv6 address leads to, for the ways of writing one
+// inside the other
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let [high, low] = match segments {
+        // NAT64, 64:ff9b::/96
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => [high, low],
+        // 6to4, 2002::/16
+        [0x2002, high, low, ..] => [high, low],
+        // Mapped ::ffff:0:0/96 and compatible ::/96, but not :: and ::1
+        _ => {
+            return ip
+                .to_ipv4()
+                .filter(|_| !ip.is_unspecified() && !ip.is_loopback())
+        }
+    };
+    Some(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)))
+}
+
+// Everything that can be checked without resolving the host. Returns the
+// host if it is a name rather than an address.
+fn check_url(url: &str) -> Result<Option<(String, u16)>, String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("Invalid callback_url: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err("callback_url must be an http or https URL".to_string());
+    }
+    let Some(host) = parsed.host_str() else {
+        return Err("callback_url must have a host".to_string());
+    };
+    let port = parsed.port_or_known_default().unwrap_or(80);
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let public = if let Ok(ip) = host.parse() {
+        is_public(ip)
+    } else {
+        let host = host.trim_end_matches('.').to_lowercase();
+        !["localhost", "internal"]
+            .iter()
+            .any(|private| host == *private || host.ends_with(&format!(".{private}")))
+    };
+    if !public {
+        return Err("callback_url must be a public address".to_string());
+    }
+    Ok(host
+        .parse::<IpAddr>()
+        .is_err()
+        .then(|| (host.to_string(), port)))
+}
+
+// Resolve a host, refusing it if any of its addresses aren't public
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Unable to resolve {host}: {e}"))?
+        .collect::<Vec<_>>();
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(format!("{host} does not resolve to a public address"));
+    }
+    Ok(addrs)
+}
+
+/// Check that a callback URL is somewhere the server is willing to send to
+pub async fn validate_url(url: &str) -> Result<(), RrgError> {
+    if let Some((host, port)) = check_url(url).map_err(RrgError::BadRequest)? {
+        resolve_public(&host, port)
+            .await
+            .map_err(|e| RrgError::BadRequest(format!("Invalid callback_url: {e}")))?;
+    }
+    Ok(())
+}
+
+// Checks addresses again at delivery, since the host may resolve differently
+// by then
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Remember where to send a ticket's numbers once it is fulfilled
+pub async fn register(
+    conn: &mut deadpool_redis::Connection,
+    guid: Uuid,
+    url: String,
+    secret: String,
+    expiry: Duration,
+) -> anyhow::Result<()> {
+    conn.set_ex::<_, _, ()>(
+        webhook_key(guid),
+        serde_json::to_string(&Webhook { url, secret })?,
+        expiry.as_secs(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// If a newly fulfilled ticket has a callback, queue its delivery
+pub async fn enqueue(
+    conn: &mut deadpool_redis::Connection,
+    waiter: &Waiter,
+    signer: &Signer,
+) -> anyhow::Result<()> {
+    let webhook: Option<String> = conn.get_del(webhook_key(waiter.id)).await?;
+    let Some(webhook) = webhook else {
+        return Ok(());
+    };
+    let Webhook { url, secret } = serde_json::from_str(&webhook)?;
+
+    let submissions = waiter::values(conn, waiter.id).await?;
+    let body = serde_json::to_string(&Delivery::new(waiter, &submissions, None, signer))?;
+
+    let id = Uuid::now_v7();
+    tracing::debug!("Queueing webhook delivery {id} for {} to {url}", waiter.id);
+    conn.set::<_, _, ()>(
+        delivery_key(id),
+        serde_json::to_string(&OutboxEntry {
+            url,
+            secret,
+            body,
+            attempts: 0,
+            status: Status::Pending,
+        })?,
+    )
+    .await?;
+    conn.zadd::<_, _, _, ()>(OUTBOX, id, now_millis()).await?;
+    Ok(())
+}
+
+fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl OutboxEntry {
+    // Count an attempt. Returns how long to wait before trying again, or None
+    // once the delivery has succeeded or been given up on.
+    fn settle(&mut self, id: Uuid, result: anyhow::Result<()>) -> Option<Duration> {
+        self.attempts += 1;
+        match result {
+            Ok(()) => {
+                tracing::info!("Delivered webhook {id} to {}", self.url);
+                self.status = Status::Delivered;
+                None
+            }
+            Err(e) if self.attempts >= MAX_ATTEMPTS => {
+                tracing::error!(
+                    "Giving up on webhook {id} to {} after {} attempts: {e:?}",
+                    self.url,
+                    self.attempts
+                );
+                self.status = Status::Failed;
+                None
+            }
+            Err(e) => {
+                let backoff = Duration::from_secs(2_u64.pow(self.attempts));
+                tracing::warn!(
+                    "Webhook {id} to {} failed, retrying in {backoff:?}: {e:?}",
+                    self.url
+                );
+                Some(backoff)
+            }
+        }
+    }
+}
+
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("Unable to build webhook HTTP client")
+}
+
+async fn attempt(client: &reqwest::Client, id: Uuid, entry: &OutboxEntry) -> anyhow::Result<()> {
+    // Addresses aren't resolved, so the resolver never sees them
+    check_url(&entry.url).map_err(anyhow::Error::msg)?;
+
+    let timestamp = now_millis();
+    let response = client
+        .post(&entry.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("x-rrg-delivery", id.to_string())
+        .header("x-rrg-timestamp", timestamp.to_string())
+        .header(
+            "x-rrg-signature",
+            sign(&entry.secret, timestamp, &entry.body),
+        )
+        .body(entry.body.clone())
+        .send()
+        .await?;
+    // Redirects count as failures, they aren't followed
+    if !response.status().is_success() {
+        anyhow::bail!("Callback responded with {}", response.status());
+    }
+    Ok(())
+}
+
+async fn deliver(
+    conn: &mut deadpool_redis::Connection,
+    client: &reqwest::Client,
+    id: Uuid,
+) -> anyhow::Result<()> {
+    let entry: Option<String> = conn.get(delivery_key(id)).await?;
+    let Some(entry) = entry else {
+        conn.zrem::<_, _, ()>(OUTBOX, id).await?;
+        return Ok(());
+    };
+    let mut entry: OutboxEntry = serde_json::from_str(&entry)?;
+
+    let result = attempt(client, id, &entry).await;
+    if let Some(backoff) = entry.settle(id, result) {
+        conn.set::<_, _, ()>(delivery_key(id), serde_json::to_string(&entry)?)
+            .await?;
+        conn.zadd::<_, _, _, ()>(
+            OUTBOX,
+            id,
+            now_millis() + u64::try_from(backoff.as_millis())?,
+        )
+        .await?;
+        return Ok(());
+    }
+
+    conn.set_ex::<_, _, ()>(
+        delivery_key(id),
+        serde_json::to_string(&entry)?,
+        HISTORY.as_secs(),
+    )
+    .await?;
+    conn.zrem::<_, _, ()>(OUTBOX, id).await?;
+    Ok(())
+}
+
+/// Work through the outbox forever. Safe to run on every instance.
+pub async fn run_outbox(redis: std::sync::Arc<deadpool_redis::Pool>) {
+    let client = client();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        let mut conn = match redis.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!("Unable to get Redis connection for webhooks: {e:?}");
+                continue;
+            }
+        };
+
+        let now = now_millis();
+        let due: Vec<Uuid> = match LEASE_SCRIPT
+            .key(OUTBOX)
+            .arg(now)
+            .arg(now + u64::try_from(LEASE.as_millis()).unwrap())
+            .arg(BATCH_SIZE)
+            .invoke_async(&mut conn)
+            .await
+        {
+            Ok(due) => due,
+            Err(e) => {
+                tracing::error!("Unable to read webhook outbox: {e:?}");
+                continue;
+            }
+        };
+
+        for id in due {
+            if let Err(e) = deliver(&mut conn, &client, id).await {
+                tracing::error!("Error processing webhook {id}: {e:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    };
+
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+
+    use super::*;
+
+    #[test]
+    fn refuses_private_callbacks() {
+        for url in [
+            "ftp://example.com/",
+            "http://127.0.0.1/",
+            "http://2130706433/",
+            "http://localhost:8080/",
+            "http://api.localhost/",
+            "http://10.0.0.1/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fdaa::3]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::10.0.0.1]/",
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[64:ff9b:1::1]/",
+            "http://[2002:c0a8:101::1]/",
+            "http://[2002:7f00:1::]/",
+            "http://[2001:db8::1]/",
+            "http://[2001::1]/",
+            "http://198.18.0.1/",
+            "http://198.19.255.255/",
+            "http://240.0.0.1/",
+            "http://255.255.255.255/",
+            "http://192.0.0.8/",
+            "http://top1.nearest.of.app.internal/",
+            "http://app.internal./",
+        ] {
+            assert!(check_url(url).is_err(), "{url} was allowed");
+        }
+    }
+
+    #[test]
+    fn allows_public_callbacks() {
+        assert_eq!(
+            check_url("https://example.com/hook"),
+            Ok(Some(("example.com".to_string(), 443)))
+        );
+        assert_eq!(check_url("http://93.184.215.14:8080/"), Ok(None));
+        assert_eq!(check_url("http://[2606:4700::1111]/"), Ok(None));
+        assert_eq!(check_url("http://[64:ff9b::5db8:d70e]/"), Ok(None));
+        assert_eq!(check_url("http://[2002:5db8:d70e::1]/"), Ok(None));
+    }
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        status: Arc<AtomicU16>,
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: String,
+    ) -> axum::http::StatusCode {
+        receiver.received.lock().unwrap().push((headers, body));
+        axum::http::StatusCode::from_u16(receiver.status.load(Ordering::SeqCst)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_callbacks_with_backoff() {
+        let receiver = Receiver::default();
+        receiver.status.store(500, Ordering::SeqCst);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // The listener is on loopback, which real callbacks can't reach
+        let client = reqwest::Client::builder()
+            .resolve("callback.test", addr)
+            .build()
+            .unwrap();
+        let id = Uuid::now_v7();
+        let mut entry = OutboxEntry {
+            url: format!("http://callback.test:{}/hook", addr.port()),
+            secret: "secret".to_string(),
+            body: r#"{"numbers":["4"]}"#.to_string(),
+            attempts: 0,
+            status: Status::Pending,
+        };
+
+        for backoff in [2, 4, 8] {
+            let result = attempt(&client, id, &entry).await;
+            assert!(result.is_err());
+            assert_eq!(entry.settle(id, result), Some(Duration::from_secs(backoff)));
+            assert!(matches!(entry.status, Status::Pending));
+        }
+
+        receiver.status.store(204, Ordering::SeqCst);
+        let result = attempt(&client, id, &entry).await;
+        assert!(result.is_ok());
+        assert_eq!(entry.settle(id, result), None);
+        assert_eq!(entry.attempts, 4);
+        assert!(matches!(entry.status, Status::Delivered));
+
+        let requests = receiver.received.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        for (headers, body) in requests.iter() {
+            assert_eq!(body, &entry.body);
+            assert_eq!(headers["x-rrg-delivery"], id.to_string());
+            let timestamp = headers["x-rrg-timestamp"].to_str().unwrap();
+            let signature = headers["x-rrg-signature"].to_str().unwrap();
+
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(format!("{timestamp}.{body}").as_bytes());
+            let expected = hex::encode(mac.finalize().into_bytes());
+            assert_eq!(signature, format!("sha256={expected}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_eventually() {
+        let id = Uuid::now_v7();
+        let mut entry = OutboxEntry {
+            url: "http://127.0.0.1:9/hook".to_string(),
+            secret: "secret".to_string(),
+            body: "{}".to_string(),
+            attempts: MAX_ATTEMPTS - 1,
+            status: Status::Pending,
+        };
+        // Refused before anything is sent
+        let result = attempt(&reqwest::Client::new(), id, &entry).await;
+        assert!(result.is_err());
+        assert_eq!(entry.settle(id, result), None);
+        assert!(matches!(entry.status, Status::Failed));
+    }
+}
@@ -10,10 +10,11 @@ use axum::{
     routing::get,
     Router,
 };
-use futures_util::FutureExt;
+use redis::AsyncCommands as _;
 use tokio::time::{timeout, Duration};
+use uuid::Uuid;
 
-use crate::{error::RrgError, state::AppState};
+use crate::{error::RrgError, state::AppState, submission::now_millis};
 
 #[tracing::instrument]
 async fn ws_handler(
@@ -21,15 +22,63 @@ async fn ws_handler(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| {
-        handle_socket(socket, addr, state).map(|res| {
-            if let Err(e) = res {
-                tracing::error!("Error in websocket: {:?}", e);
+    ws.on_upgrade(move |socket| async move {
+        let redis = state.redis.clone();
+        let id = Uuid::new_v4();
+        let alive = async {
+            let mut interval = tokio::time::interval(WATCHER_REFRESH);
+            loop {
+                interval.tick().await;
+                mark_watcher(&redis, id, true).await;
             }
-        })
+        };
+        tokio::select! {
+            result = handle_socket(socket, addr, state) => {
+                if let Err(e) = result {
+                    tracing::error!("Error in websocket: {:?}", e);
+                }
+            }
+            () = alive => {}
+        }
+        mark_watcher(&redis, id, false).await;
     })
 }
 
+// Open provider pages across every instance, scored by when each was last seen
+// open. Pages on an instance that died without removing them stop counting once
+// they haven't been seen for a while.
+const WATCHERS: &str = "provider_watchers_seen";
+const WATCHER_REFRESH: Duration = Duration::from_secs(10);
+const WATCHER_EXPIRY: Duration = Duration::from_secs(30);
+
+async fn mark_watcher(redis: &deadpool_redis::Pool, id: Uuid, open: bool) {
+    let result = async {
+        let mut conn = redis.get().await?;
+        if open {
+            conn.zadd::<_, _, _, ()>(WATCHERS, id, now_millis()).await?;
+        } else {
+            conn.zrem::<_, _, ()>(WATCHERS, id).await?;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        tracing::error!("Unable to update provider watchers: {e:?}");
+    }
+}
+
+/// How many providers currently have the site open
+pub async fn watchers(conn: &mut deadpool_redis::Connection) -> anyhow::Result<u64> {
+    let cutoff = now_millis().saturating_sub(u64::try_from(WATCHER_EXPIRY.as_millis())?);
+    let (watchers,): (u64,) = redis::pipe()
+        .zrembyscore(WATCHERS, "-inf", cutoff)
+        .ignore()
+        .zcard(WATCHERS)
+        .query_async(conn)
+        .await?;
+    Ok(watchers)
+}
+
 #[tracing::instrument]
 async fn heartbeat(socket: &mut WebSocket) -> bool {
     socket